@@ -0,0 +1,150 @@
+use std::{cell::RefCell, collections::HashMap, fmt::Debug, rc::Rc};
+
+use crate::{
+    symbol::InternedSymbol,
+    value::{Dictionary, Object, Value, ValueError},
+};
+
+/// A set of named functions, which can be implemented differently for each type.
+/// Calling a method dispatches on the type of its first argument
+pub struct Behaviour {
+    pub methods: Vec<InternedSymbol>,
+    /// The methods implemented for each type
+    implementations: RefCell<HashMap<Value, Dictionary>>,
+}
+
+impl Debug for Behaviour {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("behaviour {")?;
+        for (i, m) in self.methods.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str(&m.name())?;
+        }
+        f.write_str("}")
+    }
+}
+
+/// The behaviours which native functions dispatch through
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    Eq,
+    Show,
+    Ord,
+}
+
+impl Builtin {
+    pub fn resolve_symbol(name: &str) -> Option<Builtin> {
+        match name {
+            "Eq" => Some(Builtin::Eq),
+            "Show" => Some(Builtin::Show),
+            "Ord" => Some(Builtin::Ord),
+            _ => None,
+        }
+    }
+}
+
+/// The builtin behaviours of one run of a program. Each run gets its own,
+/// so that implementations given in one don't leak into others
+pub struct Builtins {
+    eq: Rc<Behaviour>,
+    show: Rc<Behaviour>,
+    ord: Rc<Behaviour>,
+}
+
+impl Default for Builtins {
+    fn default() -> Self {
+        Builtins {
+            eq: Rc::new(Behaviour::with_method_names(&["="])),
+            show: Rc::new(Behaviour::with_method_names(&["print"])),
+            ord: Rc::new(Behaviour::with_method_names(&["lt", "lte", "gt", "gte"])),
+        }
+    }
+}
+
+impl Builtins {
+    pub fn get(&self, builtin: Builtin) -> &Rc<Behaviour> {
+        match builtin {
+            Builtin::Eq => &self.eq,
+            Builtin::Show => &self.show,
+            Builtin::Ord => &self.ord,
+        }
+    }
+}
+
+impl Behaviour {
+    pub fn new(methods: Vec<InternedSymbol>) -> Self {
+        Behaviour {
+            methods,
+            implementations: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn with_method_names(methods: &[&str]) -> Self {
+        Behaviour::new(methods.iter().map(|m| InternedSymbol::new(m)).collect())
+    }
+
+    /// Add the methods in the dictionary to the implementation for the type
+    pub fn implement(&self, type_of: Value, methods: Dictionary) -> Result<(), ValueError> {
+        for method in methods.keys() {
+            match method {
+                Value::Symbol(s) if self.methods.contains(s) => (),
+                _ => return Err(ValueError::UnknownMethod),
+            }
+        }
+        self.implementations
+            .borrow_mut()
+            .entry(type_of)
+            .or_default()
+            .extend(methods);
+        Ok(())
+    }
+
+    /// The function implementing the method for the type of the value, if there is one
+    pub fn find(&self, method: InternedSymbol, value: &Value) -> Option<Value> {
+        let implementations = self.implementations.borrow();
+        // Builtin natives check for implementations on every call, so skip working out the type
+        if implementations.is_empty() {
+            return None;
+        }
+        implementations
+            .get(&value.type_of())?
+            .get(&Value::Symbol(method))
+            .cloned()
+    }
+}
+
+impl Value {
+    /// The symbol naming the type of this value, which behaviours dispatch on.
+    /// Dictionaries and modules with a `type` member are records of that type
+    pub fn type_of(&self) -> Value {
+        let record_type = || Value::Symbol(InternedSymbol::new("type"));
+        let name = match self {
+            Value::Int(_) | Value::BigInt(_) | Value::Rational(_) | Value::Float(_) => "number",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::Uninit => "uninit",
+            Value::Symbol(_) => "symbol",
+            Value::List(_) => "list",
+            Value::Dictionary(d) => match d.get(&record_type()) {
+                Some(t) => return t.clone(),
+                None => "dict",
+            },
+            Value::NativeFunction(_) => "function",
+            Value::Object(o) => match o {
+                Object::Closure(_) | Object::Method(_, _) => "function",
+                Object::String(_) => "string",
+                Object::Box(_) => "box",
+                Object::Module(m) => match m.get(&record_type()) {
+                    Some(t) => return t.clone(),
+                    None => "module",
+                },
+                Object::Behaviour(_) => "behaviour",
+                Object::Coroutine(_) => "coroutine",
+                Object::Launch(_) => "launch",
+            },
+        };
+        Value::Symbol(InternedSymbol::new(name))
+    }
+}
@@ -1,17 +1,103 @@
-use std::{collections::HashMap, iter::repeat, rc::Rc};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use crate::{
-    expression::{Block, Expression, Let, Literal, LocatedExpression, Symbol},
+    behaviour::Builtin,
+    diagnostic::render_span,
+    expression::{
+        Block, Expression, Let, Literal, LocatedExpression, LocatedPattern, Pattern, Symbol,
+    },
     native_function::NativeFunction,
-    opcode::{CaptureIndex, ConstantIndex, FunctionIndex, OpCode, RegisterIndex, ValueIndex},
-    value::{Function, Placeholder, Value, ValueError},
+    opcode::{
+        CaptureIndex, ConstantIndex, FunctionIndex, OpCode, RegisterIndex, SpanIndex, ValueIndex,
+        VecIndex, VecOffset,
+    },
+    tokeniser::{Location, Span},
+    value::{Function, Value},
 };
 
-#[derive(Debug)]
-pub enum CompilerError {
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
+pub enum CompilerErrorType {
     NoFrames,
     NoElementsInLet,
     NoNativeSymbol,
+    NonConstantLiteral,
+    /// letrec and modules can only bind plain symbols
+    NonSymbolBinding,
+    /// The static module doesn't have a member with this name
+    UnknownMember(Symbol),
+    /// A function can't refer to more constants than a `ConstantIndex` can hold
+    TooManyConstants,
+    /// A function can't refer to more spans than a `SpanIndex` can hold
+    TooManySpans,
+}
+
+impl CompilerErrorType {
+    fn with_span(self, span: Span) -> CompilerError {
+        CompilerError {
+            error_type: self,
+            span: Some(span),
+        }
+    }
+}
+
+impl Display for CompilerErrorType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompilerErrorType::NoFrames => f.write_str("no frame to compile into"),
+            CompilerErrorType::NoElementsInLet => f.write_str("let has nothing to bind"),
+            CompilerErrorType::NoNativeSymbol => f.write_str("symbol isn't defined"),
+            CompilerErrorType::NonConstantLiteral => {
+                f.write_str("literal can't be evaluated at compile time")
+            }
+            CompilerErrorType::NonSymbolBinding => {
+                f.write_str("letrec and modules can only bind symbols")
+            }
+            CompilerErrorType::UnknownMember(member) => {
+                f.write_fmt(format_args!("the module has no member {}", member.0))
+            }
+            CompilerErrorType::TooManyConstants => {
+                f.write_str("the function has too many constants")
+            }
+            CompilerErrorType::TooManySpans => {
+                f.write_str("the function has too many expressions which can fail")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompilerError {
+    pub error_type: CompilerErrorType,
+    /// The innermost expression being compiled when the error happened
+    pub span: Option<Span>,
+}
+
+impl From<CompilerErrorType> for CompilerError {
+    fn from(value: CompilerErrorType) -> Self {
+        CompilerError {
+            error_type: value,
+            span: None,
+        }
+    }
+}
+
+impl Display for CompilerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.error_type, f)
+    }
+}
+
+impl CompilerError {
+    /// The error with the line of source it's on, the way rustc shows errors
+    pub fn render(&self) -> String {
+        render_span(&self.to_string(), self.span.as_ref())
+    }
 }
 
 type Result<T> = std::result::Result<T, CompilerError>;
@@ -26,7 +112,7 @@ pub enum Local {
     None,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CompilerFrame {
     pub names: HashMap<(usize, Symbol), ValueIndex>,
     pub locals: Vec<Local>,
@@ -36,14 +122,26 @@ pub struct CompilerFrame {
     pub opcodes: Vec<OpCode>,
     pub constants: Vec<Value>,
     pub functions: Vec<Rc<Function>>,
+    pub spans: Vec<Span>,
+    /// The span of the expression each opcode was compiled from
+    pub locations: Vec<Option<Span>>,
+    /// The span of the expression being compiled, which opcodes are pushed with
+    pub location: Option<Span>,
+    /// The symbol the function being compiled is bound to
+    pub name: Option<Rc<str>>,
+    /// The members of static modules bound to names, keyed the same way as `names`
+    pub layouts: HashMap<(usize, Symbol), Vec<Symbol>>,
 }
 
 impl CompilerFrame {
-    fn to_function(self, arity: usize) -> Function {
+    fn into_function(self, arity: usize) -> Function {
         Function {
             opcodes: self.opcodes,
             constants: self.constants,
             functions: self.functions,
+            spans: self.spans,
+            locations: self.locations,
+            name: self.name,
             arity,
             num_captures: self.captures.len(),
             num_registers: self.locals.len(),
@@ -57,9 +155,11 @@ impl CompilerFrame {
     fn reduce_scope(&mut self) {
         self.depth -= 1;
         self.names.retain(|(depth, _), _| *depth <= self.depth);
+        self.layouts.retain(|(depth, _), _| *depth <= self.depth);
     }
 
     fn assign_name(&mut self, name: &Symbol, register: ValueIndex) {
+        self.layouts.remove(&(self.depth, name.clone()));
         self.names.insert((self.depth, name.clone()), register);
     }
 
@@ -82,34 +182,40 @@ impl CompilerFrame {
         }
     }
 
-    /// Create a new compiler frame with the given arguments and depth
-    pub fn new(arguments: &Vec<Symbol>, depth: usize) -> Self {
+    /// Create a new compiler frame with the given number of arguments and depth.
+    /// The arguments are in the first registers, and are reserved until they're bound
+    pub fn new(arity: usize, depth: usize) -> Self {
         CompilerFrame {
-            locals: repeat(Local::ToClear).take(arguments.len()).collect(),
-            names: HashMap::from_iter(arguments.iter().enumerate().map(|(i, s)| {
-                (
-                    (depth, s.clone()),
-                    ValueIndex::Register(RegisterIndex(i as u8)),
-                )
-            })),
+            locals: std::iter::repeat_n(Local::Reserved, arity).collect(),
+            names: HashMap::new(),
             captures: vec![],
             depth: depth + 1,
             opcodes: vec![],
             constants: vec![],
             functions: vec![],
+            spans: vec![],
+            locations: vec![],
+            location: None,
+            name: None,
+            layouts: HashMap::new(),
         }
     }
 
     /// Finds the symbol with the same name at the greatest depth <= self.depth,
     fn find_local(&self, symbol: &Symbol) -> Option<ValueIndex> {
+        self.find_local_key(symbol).map(|k| self.names[&k].clone())
+    }
+
+    /// Finds the key in `names` which the symbol currently refers to
+    fn find_local_key(&self, symbol: &Symbol) -> Option<(usize, Symbol)> {
         self.names
-            .iter()
-            .filter(|((d, s), _)| {
+            .keys()
+            .filter(|(d, s)| {
                 debug_assert!(*d <= self.depth);
                 s == symbol
             })
-            .max_by_key(|((d, _), _)| d)
-            .map(|(_, i)| i.clone())
+            .max_by_key(|(d, _)| d)
+            .cloned()
     }
 
     /// If the capture index already exists in captures, return its position,
@@ -149,9 +255,29 @@ impl CompilerFrame {
         }
     }
 
-    fn add_literal(&mut self, literal: &Literal) -> ConstantIndex {
-        self.constants.push((literal.clone()).into());
-        ConstantIndex((self.constants.len() - 1) as u8)
+    fn add_constant(&mut self, value: Value) -> Result<ConstantIndex> {
+        let index =
+            u8::try_from(self.constants.len()).map_err(|_| CompilerErrorType::TooManyConstants)?;
+        self.constants.push(value);
+        Ok(ConstantIndex(index))
+    }
+
+    fn add_literal(&mut self, literal: &Literal) -> Result<ConstantIndex> {
+        let value = literal
+            .try_into()
+            .map_err(|_| CompilerErrorType::NonConstantLiteral)?;
+        self.add_constant(value)
+    }
+
+    fn push_opcode(&mut self, opcode: OpCode) {
+        self.opcodes.push(opcode);
+        self.locations.push(self.location.clone());
+    }
+
+    fn add_span(&mut self, location: &Location) -> Result<SpanIndex> {
+        let index = u8::try_from(self.spans.len()).map_err(|_| CompilerErrorType::TooManySpans)?;
+        self.spans.push(location.into());
+        Ok(SpanIndex(index))
     }
 
     fn compile_literal(
@@ -159,21 +285,39 @@ impl CompilerFrame {
         position: Option<RegisterIndex>,
         literal: &Literal,
     ) -> Result<ValueIndex> {
-        let lit_pos = self.add_literal(literal);
-        Ok(match position {
+        let lit_pos = self.add_literal(literal)?;
+        Ok(self.place_constant(position, lit_pos))
+    }
+
+    /// Copy the constant into the position if one is given, otherwise refer to it directly
+    fn place_constant(
+        &mut self,
+        position: Option<RegisterIndex>,
+        constant: ConstantIndex,
+    ) -> ValueIndex {
+        match position {
             Some(p) => {
-                self.opcodes
-                    .push(OpCode::CopyValue(ValueIndex::Constant(lit_pos), p.clone()));
+                self.push_opcode(OpCode::CopyValue(ValueIndex::Constant(constant), p.clone()));
                 ValueIndex::Register(p)
             }
-            None => ValueIndex::Constant(lit_pos),
-        })
+            None => ValueIndex::Constant(constant),
+        }
     }
 }
 
-#[derive(Debug)]
+/// The symbol the pattern binds the whole value to, if it does
+fn bound_symbol<'b>(pattern: &'b LocatedPattern<'_>) -> Option<&'b Symbol> {
+    match &pattern.pattern {
+        Pattern::Bind(symbol) => Some(symbol),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Compiler {
     frames: Vec<CompilerFrame>,
+    /// The symbol the function about to be compiled is bound to
+    binding: Option<Symbol>,
 }
 
 impl Compiler {
@@ -181,19 +325,16 @@ impl Compiler {
         self.frames
             .last_mut()
             .map(|f| f.clear_unused_locals())
-            .ok_or(CompilerError::NoFrames)
+            .ok_or(CompilerErrorType::NoFrames.into())
     }
 
     /// Set the local at the given index to ToClear
     fn drop_register(&mut self, index: ValueIndex) -> Result<()> {
-        match index {
-            ValueIndex::Register(RegisterIndex(i)) => {
-                let ls = &mut self.frames.last_mut().unwrap().locals;
-                if ls[i as usize] == Local::Reserved {
-                    ls[i as usize] = Local::ToClear;
-                }
+        if let ValueIndex::Register(RegisterIndex(i)) = index {
+            let ls = &mut self.frames.last_mut().unwrap().locals;
+            if ls[i as usize] == Local::Reserved {
+                ls[i as usize] = Local::ToClear;
             }
-            _ => (),
         }
         Ok(())
     }
@@ -201,7 +342,7 @@ impl Compiler {
     fn reserve_next_free_register(&mut self) -> Result<(RegisterIndex, &mut Local)> {
         self.frames
             .last_mut()
-            .ok_or(CompilerError::NoFrames)
+            .ok_or(CompilerErrorType::NoFrames.into())
             .map(|f| f.reserve_next_free_register())
     }
 
@@ -210,10 +351,25 @@ impl Compiler {
     }
 
     fn push_opcode(&mut self, opcode: OpCode) -> Result<usize> {
-        self.frames.last_mut().unwrap().opcodes.push(opcode);
+        self.frames.last_mut().unwrap().push_opcode(opcode);
         Ok(self.frames.last().unwrap().opcodes.len() - 1)
     }
 
+    /// Point the jump at the given position to the next opcode to be pushed
+    fn patch_jump(&mut self, position: usize) -> Result<()> {
+        let frame = self.frames.last_mut().ok_or(CompilerErrorType::NoFrames)?;
+        let offset = (frame.opcodes.len() - position) as VecOffset;
+        match &mut frame.opcodes[position] {
+            OpCode::Jump(o)
+            | OpCode::JumpIfNotEqual(_, _, o)
+            | OpCode::JumpIfNotList(_, _, _, o)
+            | OpCode::JumpIfMissingKey(_, _, _, o)
+            | OpCode::PushHandler(_, o) => *o = offset,
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
     fn increase_scope(&mut self) {
         self.frames.last_mut().unwrap().increase_scope()
     }
@@ -226,7 +382,7 @@ impl Compiler {
         self.frames
             .last_mut()
             .map(|f| f.assign_name(symbol, register))
-            .ok_or(CompilerError::NoFrames)
+            .ok_or(CompilerErrorType::NoFrames.into())
     }
 
     fn find_nonlocal_symbol(&mut self, frame: usize, symbol: &Symbol) -> Option<ValueIndex> {
@@ -255,20 +411,102 @@ impl Compiler {
         position: Option<RegisterIndex>,
         literal: &Literal,
     ) -> Result<ValueIndex> {
-        self.frames
-            .last_mut()
-            .unwrap()
-            .compile_literal(position, literal)
+        match literal {
+            Literal::List(elements) if !literal.is_constant() => {
+                self.compile_list(position, elements)
+            }
+            Literal::Dictionary(pairs) if !literal.is_constant() => {
+                self.compile_dictionary(position, pairs)
+            }
+            _ => self
+                .frames
+                .last_mut()
+                .unwrap()
+                .compile_literal(position, literal),
+        }
+    }
+
+    /// Compile a list literal with non-constant elements,
+    /// which has to be built at runtime
+    fn compile_list(
+        &mut self,
+        position: Option<RegisterIndex>,
+        elements: &[LocatedExpression],
+    ) -> Result<ValueIndex> {
+        let mut element_indices = vec![];
+        for element in elements {
+            element_indices.push(self.compile_expression(None, element, false)?.unwrap());
+        }
+        let result_pos = position.unwrap_or_else(|| self.reserve_next_free_register().unwrap().0);
+        self.push_opcode(OpCode::BuildList(result_pos.clone()))?;
+        for index in element_indices {
+            self.push_opcode(OpCode::ListElement(index.clone()))?;
+            self.drop_register(index)?;
+        }
+        self.clear_unused_locals()?;
+        Ok(ValueIndex::Register(result_pos))
+    }
+
+    /// Compile a dictionary literal with non-constant keys or values,
+    /// which has to be built at runtime
+    fn compile_dictionary(
+        &mut self,
+        position: Option<RegisterIndex>,
+        pairs: &[(LocatedExpression, LocatedExpression)],
+    ) -> Result<ValueIndex> {
+        let mut entries = vec![];
+        for (key, value) in pairs {
+            let key_index = self.compile_expression(None, key, false)?.unwrap();
+            let value_index = self.compile_expression(None, value, false)?.unwrap();
+            entries.push((key_index, value_index));
+        }
+        let result_pos = position.unwrap_or_else(|| self.reserve_next_free_register().unwrap().0);
+        self.push_opcode(OpCode::BuildDict(result_pos.clone()))?;
+        for (key_index, value_index) in entries {
+            self.push_opcode(OpCode::DictEntry(key_index.clone(), value_index.clone()))?;
+            self.drop_register(key_index)?;
+            self.drop_register(value_index)?;
+        }
+        self.clear_unused_locals()?;
+        Ok(ValueIndex::Register(result_pos))
     }
 
+    /// Compile an interpolated string, which is joined together at runtime
+    fn compile_interpolation(
+        &mut self,
+        position: Option<RegisterIndex>,
+        parts: &[LocatedExpression],
+    ) -> Result<ValueIndex> {
+        let mut part_indices = vec![];
+        for part in parts {
+            part_indices.push(self.compile_expression(None, part, false)?.unwrap());
+        }
+        let result_pos = position.unwrap_or_else(|| self.reserve_next_free_register().unwrap().0);
+        self.push_opcode(OpCode::BuildString(result_pos.clone()))?;
+        for index in part_indices {
+            self.push_opcode(OpCode::StringPart(index.clone()))?;
+            self.drop_register(index)?;
+        }
+        self.clear_unused_locals()?;
+        Ok(ValueIndex::Register(result_pos))
+    }
+
+    /// Insert the native function or builtin behaviour with the symbol's name.
+    /// Behaviours are looked up when the program runs, as each run has its own
     fn resolve_native_symbol(
         &mut self,
         position: Option<RegisterIndex>,
         symbol: &Symbol,
     ) -> Result<RegisterIndex> {
-        let func = NativeFunction::resolve_symbol(symbol).ok_or(CompilerError::NoNativeSymbol)?;
         let register = position.unwrap_or_else(|| self.reserve_next_free_register().unwrap().0);
-        self.push_opcode(OpCode::InsertNativeFunction(func, register.clone()));
+        let opcode = if let Some(func) = NativeFunction::resolve_symbol(symbol) {
+            OpCode::InsertNativeFunction(func, register.clone())
+        } else if let Some(builtin) = Builtin::resolve_symbol(&symbol.0) {
+            OpCode::InsertBuiltin(builtin, register.clone())
+        } else {
+            return Err(CompilerErrorType::NoNativeSymbol.into());
+        };
+        self.push_opcode(opcode)?;
         Ok(register)
     }
 
@@ -311,22 +549,39 @@ impl Compiler {
     fn compile_recursive_let<'a>(
         &mut self,
         position: Option<RegisterIndex>,
-        pairs: &Vec<(Symbol, LocatedExpression<'a>)>,
+        pairs: &[(LocatedPattern<'a>, LocatedExpression<'a>)],
         tail_position: bool,
     ) -> Result<Option<ValueIndex>> {
-        let ((last_symbol, last_exp), ignored) =
-            pairs.split_last().ok_or(CompilerError::NoElementsInLet)?;
-        let ignored_pointers: Vec<_> = ignored
+        let symbols = pairs
+            .iter()
+            .map(|(p, _)| match &p.pattern {
+                Pattern::Bind(symbol) => Ok(symbol),
+                _ => Err(CompilerErrorType::NonSymbolBinding.with_span((&p.location).into())),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let ((last_symbol, ignored_symbols), ((_, last_exp), ignored)) = symbols
+            .split_last()
+            .zip(pairs.split_last())
+            .ok_or(CompilerErrorType::NoElementsInLet)?;
+        let ignored_pointers: Vec<_> = ignored_symbols
             .iter()
-            .map(|(s, _)| self.declare_recursive_symbol(None, s))
+            .map(|s| self.declare_recursive_symbol(None, s))
             .collect();
         let last_pointer = self.declare_recursive_symbol(position, last_symbol);
-        for (p, (s, e)) in ignored_pointers.into_iter().zip(ignored) {
-            let pos = self.compile_expression(None, &e, false)?.unwrap();
+        for ((p, symbol), (_, e)) in ignored_pointers
+            .into_iter()
+            .zip(ignored_symbols)
+            .zip(ignored)
+        {
+            let pos = self
+                .compile_bound_expression(None, Some(symbol), e, false)?
+                .unwrap();
             self.push_opcode(OpCode::FillRecursive(pos, p)).unwrap();
             self.clear_unused_locals()?;
         }
-        if let Some(p) = self.compile_expression(None, last_exp, tail_position)? {
+        if let Some(p) =
+            self.compile_bound_expression(None, Some(last_symbol), last_exp, tail_position)?
+        {
             self.push_opcode(OpCode::FillRecursive(p.clone(), last_pointer))?;
             Ok(Some(p))
         } else {
@@ -334,26 +589,86 @@ impl Compiler {
         }
     }
 
+    /// Bind the value to the pattern, failing with the pattern's location
+    /// at runtime if it doesn't match
+    fn compile_destructure(&mut self, value: ValueIndex, pattern: &LocatedPattern) -> Result<()> {
+        let mut failure_jumps = vec![];
+        self.compile_pattern(value.clone(), &pattern.pattern, &mut failure_jumps)?;
+        if !failure_jumps.is_empty() {
+            let matched_jump = self.push_opcode(OpCode::Jump(0))?;
+            for p in failure_jumps {
+                self.patch_jump(p)?;
+            }
+            let span = self
+                .frames
+                .last_mut()
+                .ok_or(CompilerErrorType::NoFrames)?
+                .add_span(&pattern.location)?;
+            self.push_opcode(OpCode::MatchFailed(value, span))?;
+            self.patch_jump(matched_jump)?;
+        }
+        Ok(())
+    }
+
+    /// Compile the expression a let binds to the symbol.
+    /// Functions are named after the symbol, so stack traces can show them
+    fn compile_bound_expression<'a>(
+        &mut self,
+        position: Option<RegisterIndex>,
+        symbol: Option<&Symbol>,
+        expression: &LocatedExpression<'a>,
+        tail_position: bool,
+    ) -> Result<Option<ValueIndex>> {
+        if let Expression::Function(..) = expression.expression {
+            self.binding = symbol.cloned();
+        }
+        self.compile_expression(position, expression, tail_position)
+    }
+
     fn compile_non_recursive_let<'a>(
         &mut self,
         position: Option<RegisterIndex>,
-        pairs: &Vec<(Symbol, LocatedExpression<'a>)>,
+        pairs: &[(LocatedPattern<'a>, LocatedExpression<'a>)],
         tail_position: bool,
     ) -> Result<Option<ValueIndex>> {
-        let ((last_symbol, last_expression), ignored) =
-            pairs.split_last().ok_or(CompilerError::NoElementsInLet)?;
-        for (symbol, exp) in ignored {
-            match self.compile_expression(None, exp, false)? {
-                Some(i) => self.assign_name(symbol, i.clone())?,
-                None => unreachable!(),
+        let ((last_pattern, last_expression), ignored) = pairs
+            .split_last()
+            .ok_or(CompilerErrorType::NoElementsInLet)?;
+        for (pattern, exp) in ignored {
+            let i = self
+                .compile_bound_expression(None, bound_symbol(pattern), exp, false)?
+                .unwrap();
+            self.compile_destructure(i.clone(), pattern)?;
+            match &pattern.pattern {
+                Pattern::Bind(symbol) => self.record_layout(symbol, exp),
+                _ => self.drop_register(i)?,
             }
         }
-        match self.compile_expression(position, last_expression, tail_position)? {
-            Some(i) => {
-                self.assign_name(last_symbol, i.clone()).unwrap();
+        match &last_pattern.pattern {
+            Pattern::Bind(last_symbol) => {
+                match self.compile_bound_expression(
+                    position,
+                    Some(last_symbol),
+                    last_expression,
+                    tail_position,
+                )? {
+                    Some(i) => {
+                        self.assign_name(last_symbol, i.clone()).unwrap();
+                        self.record_layout(last_symbol, last_expression);
+                        Ok(Some(i))
+                    }
+                    None => Ok(None),
+                }
+            }
+            // Anything else has to be checked before returning,
+            // so can't be compiled in tail position
+            _ => {
+                let i = self
+                    .compile_expression(position, last_expression, false)?
+                    .unwrap();
+                self.compile_destructure(i.clone(), last_pattern)?;
                 Ok(Some(i))
             }
-            None => Ok(None),
         }
     }
 
@@ -361,7 +676,7 @@ impl Compiler {
         &mut self,
         recursive: bool,
         position: Option<RegisterIndex>,
-        pairs: &Vec<(Symbol, LocatedExpression<'a>)>,
+        pairs: &[(LocatedPattern<'a>, LocatedExpression<'a>)],
         tail_position: bool,
     ) -> Result<Option<ValueIndex>> {
         if recursive {
@@ -442,13 +757,17 @@ impl Compiler {
     fn compile_function<'a>(
         &mut self,
         position: Option<RegisterIndex>,
-        args: &Vec<Symbol>,
+        args: &[LocatedPattern<'a>],
         body: &LocatedExpression<'a>,
     ) -> Result<ValueIndex> {
-        self.frames.push(CompilerFrame::new(
-            args,
-            self.frames.last().unwrap().depth + 1,
-        ));
+        let mut frame = CompilerFrame::new(args.len(), self.frames.last().unwrap().depth + 1);
+        frame.name = self.binding.take().map(|s| s.0.as_str().into());
+        self.frames.push(frame);
+        for (i, arg) in args.iter().enumerate() {
+            let register = ValueIndex::Register(RegisterIndex(i as u8));
+            self.compile_destructure(register.clone(), arg)?;
+            self.drop_register(register)?;
+        }
         self.increase_scope();
 
         self.compile_expression(None, body, true)?;
@@ -459,19 +778,299 @@ impl Compiler {
 
         frame
             .functions
-            .push(Rc::new(new_frame.to_function(args.len())));
+            .push(Rc::new(new_frame.into_function(args.len())));
         let function_index = (frame.functions.len() - 1) as u8;
         let closure_index = position.unwrap_or_else(|| frame.reserve_next_free_register().0);
-        frame.opcodes.push(OpCode::CreateClosure(
+        frame.push_opcode(OpCode::CreateClosure(
             FunctionIndex(function_index),
             closure_index.clone(),
         ));
         captures
             .into_iter()
-            .for_each(|c| frame.opcodes.push(OpCode::CaptureValue(c)));
+            .for_each(|c| frame.push_opcode(OpCode::CaptureValue(c)));
         Ok(ValueIndex::Register(closure_index))
     }
 
+    fn compile_extract(
+        &mut self,
+        position: Option<RegisterIndex>,
+        symbols: &[Symbol],
+        dict: &LocatedExpression,
+    ) -> Result<ValueIndex> {
+        let dict_index = self.compile_expression(position, dict, false)?.unwrap();
+        for symbol in symbols {
+            let key = self
+                .frames
+                .last_mut()
+                .unwrap()
+                .add_literal(&Literal::Quoted(symbol.clone()))?;
+            let (register, _) = self.reserve_next_free_register()?;
+            self.push_opcode(OpCode::Extract(
+                dict_index.clone(),
+                ValueIndex::Constant(key),
+                register.clone(),
+            ))?;
+            self.assign_name(symbol, ValueIndex::Register(register.clone()))?;
+            self.drop_register(ValueIndex::Register(register))?;
+        }
+        Ok(dict_index)
+    }
+
+    fn compile_with(
+        &mut self,
+        position: Option<RegisterIndex>,
+        context: &LocatedExpression,
+        body: &LocatedExpression,
+        tail_position: bool,
+    ) -> Result<Option<ValueIndex>> {
+        let context_index = self.compile_expression(None, context, false)?.unwrap();
+        self.push_opcode(OpCode::PushContext(context_index.clone()))?;
+        self.drop_register(context_index)?;
+        self.clear_unused_locals()?;
+        let result = self.compile_expression(position, body, tail_position)?;
+        // In tail position the frame is thrown away (or replaced) when the body is done,
+        // taking the context with it
+        if !tail_position {
+            self.push_opcode(OpCode::PopContext)?;
+        }
+        Ok(result)
+    }
+
+    fn compile_launch(
+        &mut self,
+        position: Option<RegisterIndex>,
+        body: &LocatedExpression,
+    ) -> Result<ValueIndex> {
+        self.push_opcode(OpCode::PushLaunch)?;
+        // The body can't be in tail position, since the frame has to stay
+        // around to run the children once it's done
+        let result = self.compile_expression(position, body, false)?.unwrap();
+        self.push_opcode(OpCode::FinishLaunch)?;
+        self.push_opcode(OpCode::PopContext)?;
+        Ok(result)
+    }
+
+    fn compile_try(
+        &mut self,
+        position: Option<RegisterIndex>,
+        body: &LocatedExpression,
+        handler: &LocatedExpression,
+        tail_position: bool,
+    ) -> Result<Option<ValueIndex>> {
+        let result_pos = position.unwrap_or_else(|| self.reserve_next_free_register().unwrap().0);
+        let error_pos = self.reserve_next_free_register()?.0;
+        let push_handler = self.push_opcode(OpCode::PushHandler(error_pos.clone(), 0))?;
+        // The body can't be in tail position, as the handler belongs to this frame
+        self.compile_expression(Some(result_pos.clone()), body, false)?;
+        self.push_opcode(OpCode::PopHandler)?;
+        let jump_to_end = if tail_position {
+            self.push_opcode(OpCode::Return(ValueIndex::Register(result_pos.clone())))?;
+            None
+        } else {
+            Some(self.push_opcode(OpCode::Jump(0))?)
+        };
+        self.patch_jump(push_handler)?;
+        let handler_index = self.compile_expression(None, handler, false)?.unwrap();
+        if tail_position {
+            self.push_opcode(OpCode::TailCall(handler_index.clone()))?;
+        } else {
+            self.push_opcode(OpCode::Call(handler_index.clone(), result_pos.clone()))?;
+        }
+        self.push_opcode(OpCode::CallArgument(ValueIndex::Register(
+            error_pos.clone(),
+        )))?;
+        self.drop_register(handler_index)?;
+        self.drop_register(ValueIndex::Register(error_pos))?;
+        self.clear_unused_locals()?;
+        Ok(match jump_to_end {
+            Some(jump) => {
+                self.patch_jump(jump)?;
+                Some(ValueIndex::Register(result_pos))
+            }
+            None => None,
+        })
+    }
+
+    /// The members of the static module the expression evaluates to, if it's known
+    fn static_layout(&self, expression: &LocatedExpression) -> Option<Vec<Symbol>> {
+        match &expression.expression {
+            Expression::Module(Let { pairs, .. }) => pairs
+                .iter()
+                .map(|(p, _)| match &p.pattern {
+                    Pattern::Bind(symbol) => Some(symbol.clone()),
+                    _ => None,
+                })
+                .collect(),
+            Expression::Symbol(symbol) => self.find_layout(symbol),
+            _ => None,
+        }
+    }
+
+    /// Remember the layout of the module bound to the symbol, so its members
+    /// can be accessed by slot
+    fn record_layout(&mut self, symbol: &Symbol, expression: &LocatedExpression) {
+        if let Some(layout) = self.static_layout(expression) {
+            let frame = self.frames.last_mut().unwrap();
+            frame.layouts.insert((frame.depth, symbol.clone()), layout);
+        }
+    }
+
+    /// Find the layout of the static module that the symbol refers to,
+    /// looking through enclosing functions
+    fn find_layout(&self, symbol: &Symbol) -> Option<Vec<Symbol>> {
+        self.frames.iter().rev().find_map(|f| {
+            f.find_local_key(symbol)
+                .map(|key| f.layouts.get(&key).cloned())
+        })?
+    }
+
+    fn compile_module(
+        &mut self,
+        position: Option<RegisterIndex>,
+        Let { recursive, pairs }: &Let,
+    ) -> Result<ValueIndex> {
+        let names = pairs
+            .iter()
+            .map(|(p, _)| match &p.pattern {
+                Pattern::Bind(symbol) => Ok(symbol.clone()),
+                _ => Err(CompilerErrorType::NonSymbolBinding.with_span((&p.location).into())),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.increase_scope();
+        self.compile_let(*recursive, None, pairs, false)?;
+        let members = names
+            .iter()
+            .map(|n| self.resolve_symbol(n).unwrap())
+            .collect::<Vec<_>>();
+        let names_index = self.frames.last_mut().unwrap().add_constant(Value::List(
+            names.iter().map(|n| Value::Symbol(n.into())).collect(),
+        ))?;
+        let result_pos = position.unwrap_or_else(|| self.reserve_next_free_register().unwrap().0);
+        self.push_opcode(OpCode::BuildModule(
+            ValueIndex::Constant(names_index),
+            result_pos.clone(),
+        ))?;
+        for member in members {
+            self.push_opcode(OpCode::ListElement(member.clone()))?;
+            self.drop_register(member)?;
+        }
+        self.reduce_scope();
+        self.clear_unused_locals()?;
+        Ok(ValueIndex::Register(result_pos))
+    }
+
+    fn compile_member(
+        &mut self,
+        position: Option<RegisterIndex>,
+        module: &LocatedExpression,
+        member: &Symbol,
+        location: &Location,
+    ) -> Result<ValueIndex> {
+        let layout = self.static_layout(module);
+        let module_index = self.compile_expression(None, module, false)?.unwrap();
+        let result_pos = position.unwrap_or_else(|| self.reserve_next_free_register().unwrap().0);
+        match layout {
+            Some(layout) => {
+                let slot = layout.iter().position(|s| s == member).ok_or_else(|| {
+                    CompilerErrorType::UnknownMember(member.clone()).with_span(location.into())
+                })?;
+                self.push_opcode(OpCode::LoadSlot(
+                    module_index.clone(),
+                    slot as VecIndex,
+                    result_pos.clone(),
+                ))?;
+            }
+            None => {
+                let key = self
+                    .frames
+                    .last_mut()
+                    .unwrap()
+                    .add_literal(&Literal::Quoted(member.clone()))?;
+                self.push_opcode(OpCode::LoadMember(
+                    module_index.clone(),
+                    ValueIndex::Constant(key),
+                    result_pos.clone(),
+                ))?;
+            }
+        }
+        self.drop_register(module_index)?;
+        self.clear_unused_locals()?;
+        Ok(ValueIndex::Register(result_pos))
+    }
+
+    fn compile_behaviour(
+        &mut self,
+        position: Option<RegisterIndex>,
+        methods: &[Symbol],
+    ) -> Result<ValueIndex> {
+        let methods_index = self.frames.last_mut().unwrap().add_constant(Value::List(
+            methods.iter().map(|m| Value::Symbol(m.into())).collect(),
+        ))?;
+        let result_pos = position.unwrap_or_else(|| self.reserve_next_free_register().unwrap().0);
+        self.push_opcode(OpCode::CreateBehaviour(
+            ValueIndex::Constant(methods_index),
+            result_pos.clone(),
+        ))?;
+        Ok(ValueIndex::Register(result_pos))
+    }
+
+    /// Compile an import, resolving the path relative to the importing file
+    fn compile_import(
+        &mut self,
+        position: Option<RegisterIndex>,
+        path: &str,
+        location: &Location,
+    ) -> Result<ValueIndex> {
+        let resolved = Path::new(location.file)
+            .parent()
+            .map_or_else(|| PathBuf::from(path), |dir| dir.join(path));
+        let path_index = self
+            .frames
+            .last_mut()
+            .unwrap()
+            .add_literal(&Literal::String(resolved.to_string_lossy().into()))?;
+        let result_pos = position.unwrap_or_else(|| self.reserve_next_free_register().unwrap().0);
+        self.push_opcode(OpCode::Import(
+            ValueIndex::Constant(path_index),
+            result_pos.clone(),
+        ))?;
+        Ok(ValueIndex::Register(result_pos))
+    }
+
+    fn compile_context(
+        &mut self,
+        position: Option<RegisterIndex>,
+        symbol: &Symbol,
+    ) -> Result<ValueIndex> {
+        let key = self
+            .frames
+            .last_mut()
+            .unwrap()
+            .add_literal(&Literal::Quoted(symbol.clone()))?;
+        let result_pos = position.unwrap_or_else(|| self.reserve_next_free_register().unwrap().0);
+        self.push_opcode(OpCode::LoadContext(
+            ValueIndex::Constant(key),
+            result_pos.clone(),
+        ))?;
+        Ok(ValueIndex::Register(result_pos))
+    }
+
+    /// Compile an expression taking a single operand, which puts its result
+    /// into a register
+    fn compile_unary(
+        &mut self,
+        position: Option<RegisterIndex>,
+        operand: &LocatedExpression,
+        opcode: fn(ValueIndex, RegisterIndex) -> OpCode,
+    ) -> Result<ValueIndex> {
+        let operand_index = self.compile_expression(None, operand, false)?.unwrap();
+        let result_pos = position.unwrap_or_else(|| self.reserve_next_free_register().unwrap().0);
+        self.push_opcode(opcode(operand_index.clone(), result_pos.clone()))?;
+        self.drop_register(operand_index)?;
+        self.clear_unused_locals()?;
+        Ok(ValueIndex::Register(result_pos))
+    }
+
     fn compile_condition(
         &mut self,
         position: Option<RegisterIndex>,
@@ -484,10 +1083,10 @@ impl Compiler {
         let mut jump_end_pos = vec![];
         for (clause, result) in clauses {
             // Generate an expression for the clause in a new index
-            let clause_index = self.compile_expression(None, &clause, false)?.unwrap();
+            let clause_index = self.compile_expression(None, clause, false)?.unwrap();
             let prev_op_pos = self.push_opcode(OpCode::Crash)?;
             self.increase_scope();
-            self.compile_expression(Some(result_pos.clone()), &result, tail_position)?;
+            self.compile_expression(Some(result_pos.clone()), result, tail_position)?;
             self.reduce_scope();
             // Free the register for the clause
             self.drop_register(clause_index.clone())?;
@@ -513,24 +1112,173 @@ impl Compiler {
         }
     }
 
+    /// Check the value against the pattern, binding symbols in the current scope.
+    /// The positions of the jumps taken when the match fails are pushed onto `failure_jumps`
+    fn compile_pattern(
+        &mut self,
+        value: ValueIndex,
+        pattern: &Pattern,
+        failure_jumps: &mut Vec<usize>,
+    ) -> Result<()> {
+        match pattern {
+            Pattern::Wildcard => Ok(()),
+            Pattern::Bind(symbol) => self.assign_name(symbol, value),
+            Pattern::Literal(literal) => {
+                let constant = self.frames.last_mut().unwrap().add_literal(literal)?;
+                failure_jumps.push(self.push_opcode(OpCode::JumpIfNotEqual(
+                    value,
+                    ValueIndex::Constant(constant),
+                    0,
+                ))?);
+                Ok(())
+            }
+            Pattern::List(elements, rest) => {
+                let length = elements.len() as VecIndex;
+                failure_jumps.push(self.push_opcode(OpCode::JumpIfNotList(
+                    value.clone(),
+                    length,
+                    rest.is_some(),
+                    0,
+                ))?);
+                for (i, element) in elements.iter().enumerate() {
+                    self.compile_subpattern(element, failure_jumps, |r| {
+                        OpCode::IndexList(value.clone(), i as VecIndex, r)
+                    })?;
+                }
+                if let Some(rest) = rest {
+                    self.compile_subpattern(rest, failure_jumps, |r| {
+                        OpCode::ListTail(value.clone(), length, r)
+                    })?;
+                }
+                Ok(())
+            }
+            Pattern::Dictionary(entries) => {
+                for (entry, key) in entries {
+                    let key = self.frames.last_mut().unwrap().add_literal(key)?;
+                    let (register, _) = self.reserve_next_free_register()?;
+                    failure_jumps.push(self.push_opcode(OpCode::JumpIfMissingKey(
+                        value.clone(),
+                        ValueIndex::Constant(key),
+                        register.clone(),
+                        0,
+                    ))?);
+                    self.compile_pattern(
+                        ValueIndex::Register(register.clone()),
+                        entry,
+                        failure_jumps,
+                    )?;
+                    self.drop_register(ValueIndex::Register(register))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Load part of a value into a new register with the opcode from `load`,
+    /// then match it against the pattern
+    fn compile_subpattern(
+        &mut self,
+        pattern: &Pattern,
+        failure_jumps: &mut Vec<usize>,
+        load: impl FnOnce(RegisterIndex) -> OpCode,
+    ) -> Result<()> {
+        if *pattern == Pattern::Wildcard {
+            return Ok(());
+        }
+        let (register, _) = self.reserve_next_free_register()?;
+        self.push_opcode(load(register.clone()))?;
+        self.compile_pattern(
+            ValueIndex::Register(register.clone()),
+            pattern,
+            failure_jumps,
+        )?;
+        self.drop_register(ValueIndex::Register(register))
+    }
+
+    fn compile_match(
+        &mut self,
+        position: Option<RegisterIndex>,
+        value: &LocatedExpression,
+        arms: &Vec<(Pattern, LocatedExpression)>,
+        otherwise: &LocatedExpression,
+        tail_position: bool,
+    ) -> Result<Option<ValueIndex>> {
+        self.increase_scope();
+        let value_index = self.compile_expression(None, value, false)?.unwrap();
+        let result_pos = position.unwrap_or_else(|| self.reserve_next_free_register().unwrap().0);
+        let mut jump_end_pos = vec![];
+        for (pattern, result) in arms {
+            let mut jump_next_pos = vec![];
+            self.increase_scope();
+            self.compile_pattern(value_index.clone(), pattern, &mut jump_next_pos)?;
+            self.compile_expression(Some(result_pos.clone()), result, tail_position)?;
+            self.reduce_scope();
+            // The registers bound by this arm's pattern can be reused by the next one
+            self.clear_unused_locals()?;
+            jump_end_pos.push(self.push_opcode(OpCode::Jump(0))?);
+            for p in jump_next_pos {
+                self.patch_jump(p)?;
+            }
+        }
+        self.compile_expression(Some(result_pos.clone()), otherwise, tail_position)?;
+        self.drop_register(value_index)?;
+        self.clear_unused_locals()?;
+
+        self.reduce_scope();
+        for p in jump_end_pos {
+            self.patch_jump(p)?;
+        }
+        if tail_position {
+            Ok(None)
+        } else {
+            Ok(Some(ValueIndex::Register(result_pos)))
+        }
+    }
+
+    /// Opcodes are pushed with the span of the innermost expression they're compiled from,
+    /// and errors which don't know where they are are put at the expression
     pub fn compile_expression<'a>(
         &mut self,
         position: Option<RegisterIndex>,
         expression: &LocatedExpression<'a>,
         tail_position: bool,
+    ) -> Result<Option<ValueIndex>> {
+        let span = Span::from(&expression.location);
+        let frame = self.frames.last_mut().ok_or(CompilerErrorType::NoFrames)?;
+        let outer = frame.location.replace(span.clone());
+        let result = self
+            .compile_expression_inner(position, expression, tail_position)
+            .map_err(|e| CompilerError {
+                span: e.span.or(Some(span)),
+                ..e
+            });
+        if let Some(frame) = self.frames.last_mut() {
+            frame.location = outer;
+        }
+        result
+    }
+
+    fn compile_expression_inner<'a>(
+        &mut self,
+        position: Option<RegisterIndex>,
+        expression: &LocatedExpression<'a>,
+        tail_position: bool,
     ) -> Result<Option<ValueIndex>> {
         let expression_result = match &expression.expression {
             Expression::Condition(clauses, otherwise) => {
-                self.compile_condition(position, &clauses, otherwise.as_ref(), tail_position)?
+                self.compile_condition(position, clauses, otherwise.as_ref(), tail_position)?
             }
             Expression::Call(function, arguments) => {
-                self.compile_call(position, function.as_ref(), &arguments, tail_position)?
+                self.compile_call(position, function.as_ref(), arguments, tail_position)?
             }
             Expression::Let(Let { recursive, pairs }) => {
                 self.compile_let(*recursive, position, pairs, tail_position)?
             }
             Expression::Function(arguments, body) => {
-                Some(self.compile_function(position, &arguments, body.as_ref())?)
+                Some(self.compile_function(position, arguments, body.as_ref())?)
+            }
+            Expression::Extract(symbols, dict) => {
+                Some(self.compile_extract(position, symbols, dict.as_ref())?)
             }
             Expression::Block(Block {
                 scope_introducing,
@@ -539,12 +1287,59 @@ impl Compiler {
             }) => self.compile_block(
                 *scope_introducing,
                 position,
-                &ignored,
+                ignored,
                 last.as_ref(),
                 tail_position,
             )?,
-            Expression::Literal(literal) => Some(self.compile_literal(position, &literal)?),
-            Expression::Symbol(symbol) => Some(self.compile_symbol(position, &symbol)?),
+            Expression::Match(value, arms, otherwise) => self.compile_match(
+                position,
+                value.as_ref(),
+                arms,
+                otherwise.as_ref(),
+                tail_position,
+            )?,
+            Expression::With(context, body) => {
+                self.compile_with(position, context.as_ref(), body.as_ref(), tail_position)?
+            }
+            Expression::Context(symbol) => Some(self.compile_context(position, symbol)?),
+            Expression::Module(module) => Some(self.compile_module(position, module)?),
+            Expression::Member(module, member) => Some(self.compile_member(
+                position,
+                module.as_ref(),
+                member,
+                &expression.location,
+            )?),
+            Expression::Behaviour(methods) => Some(self.compile_behaviour(position, methods)?),
+            Expression::Import(path) => {
+                Some(self.compile_import(position, path, &expression.location)?)
+            }
+            Expression::Yield(value) => {
+                Some(self.compile_unary(position, value.as_ref(), OpCode::Yield)?)
+            }
+            Expression::Spawn(function) => {
+                Some(self.compile_unary(position, function.as_ref(), OpCode::Spawn)?)
+            }
+            Expression::Launch(body) => Some(self.compile_launch(position, body.as_ref())?),
+            Expression::Raise(value) => {
+                let value_index = self
+                    .compile_expression(None, value.as_ref(), false)?
+                    .unwrap();
+                self.push_opcode(OpCode::Raise(value_index.clone()))?;
+                // Nothing after a raise runs, so the raised value can stand in for the result
+                Some(value_index)
+            }
+            Expression::Try(body, handler) => {
+                self.compile_try(position, body.as_ref(), handler.as_ref(), tail_position)?
+            }
+            Expression::MakeBox(value) => {
+                Some(self.compile_unary(position, value.as_ref(), OpCode::MakeBox)?)
+            }
+            Expression::Dereference(value) => {
+                Some(self.compile_unary(position, value.as_ref(), OpCode::Dereference)?)
+            }
+            Expression::Interpolation(parts) => Some(self.compile_interpolation(position, parts)?),
+            Expression::Literal(literal) => Some(self.compile_literal(position, literal)?),
+            Expression::Symbol(symbol) => Some(self.compile_symbol(position, symbol)?),
         };
         Ok(match expression_result {
             Some(index) if tail_position => {
@@ -557,19 +1352,130 @@ impl Compiler {
 
     pub fn new() -> Compiler {
         Compiler {
-            frames: vec![CompilerFrame::new(&vec![], 0)],
+            frames: vec![CompilerFrame::new(0, 0)],
+            binding: None,
         }
     }
 
     pub fn frame_to_function(&mut self) -> Function {
         let f = self.frames.pop().unwrap();
-        f.to_function(0)
+        f.into_function(0)
+    }
+
+    /// Take the opcodes compiled into the top level so far as a function to run.
+    /// Only the names and registers are kept for code compiled later, which starts
+    /// with its own constants, functions and spans
+    pub fn take_top_level_function(&mut self) -> Function {
+        let frame = &mut self.frames[0];
+        // Names bound straight to constants are copied into registers, so they outlive the table
+        let mut constant_names = frame
+            .names
+            .iter()
+            .filter(|(_, index)| matches!(index, ValueIndex::Constant(_)))
+            .map(|(key, index)| (key.clone(), index.clone()))
+            .collect::<Vec<_>>();
+        constant_names.sort_by_key(|((depth, symbol), _)| (*depth, symbol.0.clone()));
+        for (key, constant) in constant_names {
+            let register = frame.reserve_next_free_register().0;
+            frame.push_opcode(OpCode::CopyValue(constant, register.clone()));
+            frame.names.insert(key, ValueIndex::Register(register));
+        }
+        Function {
+            opcodes: std::mem::take(&mut frame.opcodes),
+            constants: std::mem::take(&mut frame.constants),
+            functions: std::mem::take(&mut frame.functions),
+            spans: std::mem::take(&mut frame.spans),
+            locations: std::mem::take(&mut frame.locations),
+            name: None,
+            arity: 0,
+            num_captures: 0,
+            num_registers: frame.locals.len(),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Compiler;
+    use std::rc::Rc;
+
+    use crate::{opcode::OpCode, parser::parse_expression, tokeniser::Token, value::Function};
+
+    use super::{Compiler, CompilerError, CompilerErrorType};
+
+    fn try_compile_function(source: &str) -> Result<Function, CompilerError> {
+        let source: Rc<str> = source.into();
+        let tokens = Token::tokenise_source(&source, "")
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        let (_, expression) = parse_expression(&tokens).unwrap();
+        let mut compiler = Compiler::new();
+        compiler.compile_expression(None, &expression, true)?;
+        Ok(compiler.frame_to_function())
+    }
+
+    fn try_compile(source: &str) -> Result<Vec<OpCode>, CompilerError> {
+        Ok(try_compile_function(source)?.opcodes)
+    }
+
+    fn compile(source: &str) -> Vec<OpCode> {
+        try_compile(source).unwrap()
+    }
+
+    #[test]
+    fn constant_literals_are_folded() {
+        let opcodes = compile("[1, [2, <$a: \"b\">]]");
+        assert!(!opcodes
+            .iter()
+            .any(|o| matches!(o, OpCode::BuildList(_) | OpCode::BuildDict(_))));
+        let build_lists = |opcodes: &[OpCode]| {
+            opcodes
+                .iter()
+                .filter(|o| matches!(o, OpCode::BuildList(_)))
+                .count()
+        };
+        // Both lists in the function depend on its argument, so neither can be folded
+        let function = try_compile_function("(|x| [1, [x]]) 2").unwrap();
+        assert_eq!(build_lists(&function.opcodes), 0);
+        assert_eq!(build_lists(&function.functions[0].opcodes), 2);
+        assert_eq!(build_lists(&compile("{let x 2; [1, [x]]}")), 2);
+    }
+
+    #[test]
+    fn constant_indices_never_wrap() {
+        let calls = |n: usize| {
+            (0..n)
+                .map(|i| format!("f {}", i))
+                .collect::<Vec<_>>()
+                .join("; ")
+        };
+        assert!(try_compile(&format!("|f| {{{}}}", calls(256))).is_ok());
+        assert!(matches!(
+            try_compile(&format!("|f| {{{}}}", calls(257))),
+            Err(CompilerError {
+                error_type: CompilerErrorType::TooManyConstants,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn static_module_members_are_resolved() {
+        let opcodes = compile("{let m module { let a 1, b 2 }; m.b}");
+        assert!(opcodes
+            .iter()
+            .any(|o| matches!(o, OpCode::LoadSlot(_, 1, _))));
+        assert!(!opcodes.iter().any(|o| matches!(o, OpCode::LoadMember(..))));
+        assert!(matches!(
+            try_compile("{let m module { let a 1 }; m.c}"),
+            Err(CompilerError {
+                error_type: CompilerErrorType::UnknownMember(member),
+                span: Some(span),
+            }) if member.0 == "c" && span.start_pos == 27 && span.end_pos == 30
+        ));
+        // Shadowing the module forgets its layout
+        let opcodes = compile("{let m module { let a 1 }; let m <$c: 2>; m.c}");
+        assert!(opcodes.iter().any(|o| matches!(o, OpCode::LoadMember(..))));
+    }
 
     // #[test]
     // fn test_compiler_is_working() {
@@ -0,0 +1,93 @@
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Debug,
+    rc::Rc,
+};
+
+use crate::{frame::Frame, opcode::RegisterIndex, value::Value};
+
+/// Where a coroutine has got to
+pub enum CoroutineState {
+    /// Not resumed yet. Holds the function taking no arguments which is the body
+    Start(Value),
+    /// Stopped at a `yield`. Holds the stack of frames, and the register in the
+    /// last one for the value it's resumed with
+    Suspended(Vec<Frame>, RegisterIndex),
+    /// The frames are on the VM's stack
+    Running,
+    Finished,
+}
+
+/// A function which can be suspended part way through, and resumed later.
+/// Generators and the children of `launch` scopes are both coroutines
+pub struct Coroutine {
+    state: RefCell<CoroutineState>,
+}
+
+impl Debug for Coroutine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match &*self.state.borrow() {
+            CoroutineState::Start(_) => "coroutine (not started)",
+            CoroutineState::Suspended(_, _) => "coroutine (suspended)",
+            CoroutineState::Running => "coroutine (running)",
+            CoroutineState::Finished => "coroutine (finished)",
+        })
+    }
+}
+
+impl Coroutine {
+    pub fn new(function: Value) -> Self {
+        Coroutine {
+            state: RefCell::new(CoroutineState::Start(function)),
+        }
+    }
+
+    /// Take the state to resume from, marking the coroutine as running
+    pub fn start_running(&self) -> CoroutineState {
+        self.state.replace(CoroutineState::Running)
+    }
+
+    pub fn suspend(&self, frames: Vec<Frame>, register: RegisterIndex) {
+        self.state
+            .replace(CoroutineState::Suspended(frames, register));
+    }
+
+    pub fn finish(&self) {
+        self.state.replace(CoroutineState::Finished);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(*self.state.borrow(), CoroutineState::Finished)
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(*self.state.borrow(), CoroutineState::Running)
+    }
+}
+
+/// The coroutines spawned inside a `launch` scope, which are all run
+/// to completion before the scope gives its value
+#[derive(Debug, Default)]
+pub struct Launch {
+    children: RefCell<Vec<Rc<Coroutine>>>,
+    /// The child to resume next, so that they take turns
+    next: Cell<usize>,
+}
+
+impl Launch {
+    pub fn spawn(&self, child: Rc<Coroutine>) {
+        self.children.borrow_mut().push(child);
+    }
+
+    /// The next child which still has work to do, if there are any
+    pub fn next_unfinished(&self) -> Option<Rc<Coroutine>> {
+        let mut children = self.children.borrow_mut();
+        children.retain(|c| !c.is_finished());
+        if children.is_empty() {
+            return None;
+        }
+        let index = self.next.get() % children.len();
+        self.next.set(index + 1);
+        Some(children[index].clone())
+    }
+}
@@ -0,0 +1,105 @@
+use std::fmt::Write;
+
+use crate::tokeniser::{Location, Span};
+
+/// Render the message the way rustc shows errors. With a location, it's followed by
+/// the file, line and column, and the line of source with the location underlined.
+/// Locations spanning several lines are only underlined on their first
+pub fn render(message: &str, location: Option<&Location>) -> String {
+    let mut rendered = format!("error: {}", message);
+    let Some(location) = location else {
+        return rendered;
+    };
+    let (line, column) = location.line_and_column(location.start_pos);
+    let source = location.source;
+    let line_start = source[..location.start_pos]
+        .rfind('\n')
+        .map_or(0, |i| i + 1);
+    let text = source[line_start..].lines().next().unwrap_or("");
+    let underlined_end = location.end_pos.min(line_start + text.len());
+    // Tabs are kept so the underline lines up however wide they're shown
+    let indent = source[line_start..location.start_pos]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect::<String>();
+    let carets = "^".repeat(
+        source
+            .get(location.start_pos..underlined_end)
+            .map_or(0, |s| s.chars().count())
+            .max(1),
+    );
+    let gutter = line.to_string().len();
+    write!(
+        rendered,
+        "\n{:gutter$}--> {}:{}:{}\n{:gutter$} |\n{} | {}\n{:gutter$} | {}{}",
+        "", location.file, line, column, "", line, text, "", indent, carets
+    )
+    .unwrap();
+    rendered
+}
+
+/// Call the function with the span as a location, if it's within its source
+fn with_location<T>(span: &Span, f: impl FnOnce(&Location) -> T) -> Option<T> {
+    span.source.get(span.start_pos..span.end_pos)?;
+    Some(f(&Location {
+        file: &span.file,
+        source: &span.source,
+        start_pos: span.start_pos,
+        end_pos: span.end_pos,
+    }))
+}
+
+/// Render the message pointing at the span, if there is one
+pub fn render_span(message: &str, span: Option<&Span>) -> String {
+    match span {
+        Some(span) => with_location(span, |location| render(message, Some(location)))
+            .unwrap_or_else(|| format!("{}\n --> {}", render(message, None), span)),
+        None => render(message, None),
+    }
+}
+
+/// Where the span starts, as file:line:column
+pub fn describe_span(span: &Span) -> String {
+    with_location(span, |location| {
+        let (line, column) = location.line_and_column(location.start_pos);
+        format!("{}:{}:{}", location.file, line, column)
+    })
+    .unwrap_or_else(|| span.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::tokeniser::Location;
+
+    use super::render;
+
+    #[test]
+    fn errors_point_at_their_line_and_column() {
+        let source: Rc<str> = "let x 1\n\tlet ü (f x)\n".into();
+        let start_pos = source.find("(f").unwrap();
+        let location = Location {
+            file: "main.maxlang",
+            source: &source,
+            start_pos,
+            end_pos: start_pos + 5,
+        };
+        assert_eq!(
+            render("oops", Some(&location)),
+            "error: oops
+ --> main.maxlang:2:8
+  |
+2 | \tlet ü (f x)
+  | \t      ^^^^^"
+        );
+        // Locations at the end of the source still get a caret
+        let location = Location {
+            start_pos: source.len(),
+            end_pos: source.len(),
+            ..location
+        };
+        assert!(render("end", Some(&location)).ends_with(" --> main.maxlang:3:1\n  |\n3 | \n  | ^"));
+        assert_eq!(render("nowhere", None), "error: nowhere");
+    }
+}
@@ -1,5 +1,7 @@
 use std::fmt::Debug;
 
+use num_bigint::BigInt;
+
 use crate::tokeniser::Location;
 
 #[derive(PartialEq, Clone, Debug, Hash, Eq)]
@@ -7,7 +9,10 @@ pub struct Symbol(pub String);
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Literal<'a> {
-    Number(f64),
+    Int(i64),
+    /// An integer literal too big to fit into an int
+    BigInt(BigInt),
+    Float(f64),
     Nil,
     Bool(bool),
     String(String),
@@ -16,10 +21,50 @@ pub enum Literal<'a> {
     Dictionary(Vec<(LocatedExpression<'a>, LocatedExpression<'a>)>),
 }
 
+impl<'a> Literal<'a> {
+    /// Returns true if this literal only contains other constant literals,
+    /// so can be evaluated at compile time
+    pub fn is_constant(&self) -> bool {
+        fn is_constant_expression(e: &LocatedExpression) -> bool {
+            matches!(&e.expression, Expression::Literal(l) if l.is_constant())
+        }
+        match self {
+            Literal::List(elements) => elements.iter().all(is_constant_expression),
+            Literal::Dictionary(pairs) => pairs
+                .iter()
+                .all(|(k, v)| is_constant_expression(k) && is_constant_expression(v)),
+            _ => true,
+        }
+    }
+}
+
+/// A shape to check a value against, binding the parts of it that match to symbols
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern<'a> {
+    /// Matches anything without binding it
+    Wildcard,
+    /// Matches anything, binding it to the symbol
+    Bind(Symbol),
+    /// Matches values equal to the constant literal
+    Literal(Literal<'a>),
+    /// Matches lists whose elements match each pattern in turn.
+    /// With a rest pattern (.1) the list can be longer, and the leftover elements
+    /// are matched against it as a list
+    List(Vec<Pattern<'a>>, Option<Box<Pattern<'a>>>),
+    /// Matches dictionaries containing each key (.1), where the value matches the pattern (.0)
+    Dictionary(Vec<(Pattern<'a>, Literal<'a>)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocatedPattern<'a> {
+    pub pattern: Pattern<'a>,
+    pub location: Location<'a>,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Let<'a> {
     pub recursive: bool,
-    pub pairs: Vec<(Symbol, LocatedExpression<'a>)>,
+    pub pairs: Vec<(LocatedPattern<'a>, LocatedExpression<'a>)>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -49,8 +94,48 @@ pub enum Expression<'a> {
     ),
     Call(Box<LocatedExpression<'a>>, Vec<LocatedExpression<'a>>),
     Let(Let<'a>),
-    Function(Vec<Symbol>, Box<LocatedExpression<'a>>),
+    Function(Vec<LocatedPattern<'a>>, Box<LocatedExpression<'a>>),
+    /// Bind the given symbols to their values in the dictionary
+    Extract(Vec<Symbol>, Box<LocatedExpression<'a>>),
     Block(Block<'a>),
+    /// Check the value (.0) against each pattern in turn, evaluating the expression
+    /// of the first that matches, or the else expression (.2) if none do
+    Match(
+        Box<LocatedExpression<'a>>,
+        Vec<(Pattern<'a>, LocatedExpression<'a>)>,
+        Box<LocatedExpression<'a>>,
+    ),
+    /// A record of the bindings in the let, with slots laid out at compile time
+    Module(Let<'a>),
+    /// Get a member (.1) of a module or dictionary
+    Member(Box<LocatedExpression<'a>>, Symbol),
+    /// Declare a new behaviour with the given method names
+    Behaviour(Vec<Symbol>),
+    /// Run the file at the path, relative to this one, giving its final value
+    Import(String),
+    /// Suspend the running coroutine, handing the value to whatever resumed it.
+    /// Evaluates to the value the coroutine is next resumed with
+    Yield(Box<LocatedExpression<'a>>),
+    /// Run the function as a coroutine inside the innermost launch scope
+    Spawn(Box<LocatedExpression<'a>>),
+    /// Run the body, then the coroutines spawned inside it until they've all finished
+    Launch(Box<LocatedExpression<'a>>),
+    /// Unwind to the innermost enclosing try, handing it the value
+    Raise(Box<LocatedExpression<'a>>),
+    /// Evaluate the body (.0). If anything is raised while it runs,
+    /// call the handler (.1) with the error instead
+    Try(Box<LocatedExpression<'a>>, Box<LocatedExpression<'a>>),
+    /// A string joining the parts together. Parts which aren't strings
+    /// are formatted the way `print` shows them
+    Interpolation(Vec<LocatedExpression<'a>>),
+    /// Run the body (.1) with the dictionary (.0) merged into the dynamic context
+    With(Box<LocatedExpression<'a>>, Box<LocatedExpression<'a>>),
+    /// Look up a symbol in the dynamic context
+    Context(Symbol),
+    /// Put the value into a new mutable box
+    MakeBox(Box<LocatedExpression<'a>>),
+    /// Get the value out of a box
+    Dereference(Box<LocatedExpression<'a>>),
     Literal(Literal<'a>),
     Symbol(Symbol),
 }
@@ -81,7 +166,34 @@ impl<'a> Debug for Expression<'a> {
             Expression::Function(args, res) => {
                 f.write_fmt(format_args!("Fun({:?}) {:?}", args, res))
             }
+            Expression::Extract(symbols, dict) => {
+                f.write_fmt(format_args!("Extract({:?}) {:?}", symbols, dict))
+            }
             Expression::Block(block) => block.fmt(f),
+            Expression::Match(value, arms, else_arm) => f.write_fmt(format_args!(
+                "Match({:?}) {:?}, else {:?}",
+                value, arms, else_arm
+            )),
+            Expression::Module(pairs) => f.write_fmt(format_args!("Module({:?})", pairs)),
+            Expression::Member(e, member) => f.write_fmt(format_args!("{:?}.{:?}", e, member)),
+            Expression::Behaviour(methods) => f.write_fmt(format_args!("Behaviour({:?})", methods)),
+            Expression::Import(path) => f.write_fmt(format_args!("Import({:?})", path)),
+            Expression::Yield(e) => f.write_fmt(format_args!("Yield({:?})", e)),
+            Expression::Spawn(e) => f.write_fmt(format_args!("Spawn({:?})", e)),
+            Expression::Launch(e) => f.write_fmt(format_args!("Launch({:?})", e)),
+            Expression::Raise(e) => f.write_fmt(format_args!("Raise({:?})", e)),
+            Expression::Try(body, handler) => {
+                f.write_fmt(format_args!("Try({:?}) catch {:?}", body, handler))
+            }
+            Expression::Interpolation(parts) => {
+                f.write_fmt(format_args!("Interpolation({:?})", parts))
+            }
+            Expression::With(context, body) => {
+                f.write_fmt(format_args!("With({:?}) {:?}", context, body))
+            }
+            Expression::Context(s) => f.write_fmt(format_args!("^{:?}", s)),
+            Expression::MakeBox(e) => f.write_fmt(format_args!("&{:?}", e)),
+            Expression::Dereference(e) => f.write_fmt(format_args!("@{:?}", e)),
             Expression::Literal(l) => l.fmt(f),
             Expression::Symbol(s) => s.fmt(f),
         }
@@ -1,29 +1,51 @@
-use std::{
-    cell::RefCell,
-    fmt::Debug,
-    ops::{Range, RangeBounds},
-    rc::Rc,
-};
+use std::{cell::RefCell, fmt::Debug, rc::Rc};
+
+use im::Vector;
 
 use crate::{
+    behaviour::Behaviour,
+    coroutine::{Coroutine, Launch},
     native_function::NativeFunction,
-    opcode::{FunctionIndex, OpCode, RegisterIndex, ValueIndex},
-    value::{Closure, Function, Placeholder, Value},
-    vm::{CallType, RuntimeError},
+    opcode::{OpCode, RegisterIndex, ValueIndex},
+    symbol::InternedSymbol,
+    tokeniser::Span,
+    value::{Closure, Dictionary, Function, Module, Object, Placeholder, Value},
+    vm::{RuntimeError, RuntimeErrorType},
 };
 
+/// Where to go when an error is raised inside a `try`
+#[derive(Debug, Clone)]
+pub struct Handler {
+    /// The position of the handler's opcodes
+    pub catch: usize,
+    /// The register the error goes into
+    pub register: RegisterIndex,
+    /// The number of contexts when the handler was pushed
+    pub contexts: usize,
+}
+
 pub struct Frame {
     pub pointer: usize,
+    /// A call which is part way through consuming its arguments. Contains the function
+    /// to apply to the remaining arguments, and the register for the result
+    /// (or None if it's a tail call)
     pub inside_call: Option<(ValueIndex, Option<RegisterIndex>)>,
     pub registers: Vec<Placeholder>,
     pub function: Rc<Function>,
     pub captures: Vec<Value>,
     pub return_position: usize,
+    /// The stack of dynamic contexts. Each one has already been merged
+    /// with the one below it, so lookups only need to check the last
+    pub contexts: Vec<Dictionary>,
+    /// The handlers for the `try`s this frame is inside, innermost last
+    pub handlers: Vec<Handler>,
+    /// The number of frames which tail calls replaced to get to this one
+    pub tail_calls: usize,
 }
 
 type Result<T> = std::result::Result<T, RuntimeError>;
 
-impl<'a> Debug for Frame {
+impl Debug for Frame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&format!(
             "Frame (return_pos: {:?}\npointer: {:?}\nregisters: {:?}\ncaptures: {:?}){{\nfunction: {}}}",
@@ -41,10 +63,15 @@ impl<'a> Debug for Frame {
 }
 
 impl Frame {
-    pub fn new_from_closure(closure: Rc<Closure>, return_position: usize) -> Frame {
+    pub fn new_from_closure(
+        closure: Rc<Closure>,
+        return_position: usize,
+        context: Dictionary,
+    ) -> Frame {
         match closure.function.clone() {
             crate::value::ClosureType::Function(func) => {
-                let mut registers = vec![Placeholder::Value(Value::Uninit); func.num_registers];
+                // One extra register for the scratch register
+                let mut registers = vec![Placeholder::Value(Value::Uninit); func.num_registers + 1];
                 registers.splice(
                     0..func.arity,
                     closure
@@ -66,14 +93,45 @@ impl Frame {
                         })
                         .collect(),
                     return_position,
+                    contexts: vec![context],
+                    handlers: vec![],
+                    tail_calls: 0,
                 }
             }
             crate::value::ClosureType::NativeFunction(_) => unreachable!(),
         }
     }
 
-    pub fn register_range(&self) -> Range<usize> {
-        self.function.arity..(self.function.arity + self.function.num_registers)
+    /// The span of the opcode the frame is waiting on: the call it made last,
+    /// or the launch it's finishing, which runs again once the child hands back control
+    pub fn waiting_span(&self) -> Option<Span> {
+        let pointer = match self.opcode() {
+            Some(OpCode::FinishLaunch) => self.pointer,
+            _ => self.pointer.checked_sub(1)?,
+        };
+        self.function.locations.get(pointer)?.clone()
+    }
+
+    /// A register which isn't used by the compiled code, for holding the
+    /// intermediate result of an over-applied tail call
+    pub fn scratch_register(&self) -> RegisterIndex {
+        RegisterIndex(self.function.num_registers as u8)
+    }
+
+    /// The current dynamic context, which is passed on to called functions
+    pub fn context(&self) -> Dictionary {
+        self.contexts.last().cloned().unwrap_or_default()
+    }
+
+    /// The innermost launch scope
+    pub fn launch(&self) -> Result<Rc<Launch>> {
+        match self
+            .context()
+            .get(&Value::Symbol(InternedSymbol::new("launch")))
+        {
+            Some(Value::Object(Object::Launch(launch))) => Ok(launch.clone()),
+            _ => Err(RuntimeErrorType::NoLaunchScope.into()),
+        }
     }
 
     pub fn opcode(&self) -> Option<OpCode> {
@@ -121,7 +179,7 @@ impl Frame {
     ) -> Result<()> {
         let b = match self.get_value_index(check_index).unwrap() {
             Value::Bool(b) => b,
-            _ => return Err(RuntimeError::NotABoolean),
+            _ => return Err(RuntimeErrorType::NotABoolean.into()),
         };
         if !b {
             self.run_jump(offset);
@@ -131,6 +189,82 @@ impl Frame {
         Ok(())
     }
 
+    pub fn run_jump_if_not_equal(&mut self, a: ValueIndex, b: ValueIndex, offset: isize) {
+        if self.get_value_index(a).unwrap() == self.get_value_index(b).unwrap() {
+            self.pointer += 1;
+        } else {
+            self.run_jump(offset);
+        }
+    }
+
+    pub fn run_jump_if_not_list(
+        &mut self,
+        list_index: ValueIndex,
+        length: usize,
+        has_rest: bool,
+        offset: isize,
+    ) {
+        let matches = match self.get_value_index(list_index).unwrap() {
+            Value::List(l) if has_rest => l.len() >= length,
+            Value::List(l) => l.len() == length,
+            _ => false,
+        };
+        if matches {
+            self.pointer += 1;
+        } else {
+            self.run_jump(offset);
+        }
+    }
+
+    pub fn run_jump_if_missing_key(
+        &mut self,
+        dict_index: ValueIndex,
+        key_index: ValueIndex,
+        register_index: RegisterIndex,
+        offset: isize,
+    ) {
+        let key = self.get_value_index(key_index).unwrap();
+        let value = match self.get_value_index(dict_index).unwrap() {
+            Value::Dictionary(d) => d.get(&key).cloned(),
+            _ => None,
+        };
+        match value {
+            Some(v) => {
+                self.registers[register_index.0 as usize] = Placeholder::Value(v);
+                self.pointer += 1;
+            }
+            None => self.run_jump(offset),
+        }
+    }
+
+    pub fn run_index_list(
+        &mut self,
+        list_index: ValueIndex,
+        index: usize,
+        register_index: RegisterIndex,
+    ) -> Result<()> {
+        let list = self.get_value_index(list_index).unwrap().list()?;
+        let value = list
+            .get(index)
+            .ok_or(RuntimeErrorType::MissingIndex(index as i64))?;
+        self.registers[register_index.0 as usize] = Placeholder::Value(value.clone());
+        self.pointer += 1;
+        Ok(())
+    }
+
+    pub fn run_list_tail(
+        &mut self,
+        list_index: ValueIndex,
+        from: usize,
+        register_index: RegisterIndex,
+    ) -> Result<()> {
+        let list = self.get_value_index(list_index).unwrap().list()?;
+        let tail = list.skip(from.min(list.len()));
+        self.registers[register_index.0 as usize] = Placeholder::Value(Value::List(tail));
+        self.pointer += 1;
+        Ok(())
+    }
+
     pub fn run_copy_value(&mut self, from_index: ValueIndex, to_index: RegisterIndex) {
         self.registers[to_index.0 as usize] = self.get_value_index(from_index);
         self.pointer += 1;
@@ -151,14 +285,232 @@ impl Frame {
         self.pointer += 1;
     }
 
-    pub fn run_call(&mut self, call_type: CallType, function_index: ValueIndex) {
-        match call_type {
-            CallType::Tail => {
-                self.inside_call = Some((function_index, None));
-            }
-            CallType::NonTail(result_index) => {
-                self.inside_call = Some((function_index, Some(result_index)));
+    pub fn run_build_list(&mut self, index: RegisterIndex) {
+        self.pointer += 1;
+        let mut list = Vector::new();
+        while let Some(OpCode::ListElement(element)) = self.opcode() {
+            self.pointer += 1;
+            list.push_back(self.get_value_index(element).unwrap());
+        }
+        self.registers[index.0 as usize] = Placeholder::Value(Value::List(list));
+    }
+
+    pub fn run_build_dict(&mut self, index: RegisterIndex) -> Result<()> {
+        self.pointer += 1;
+        let mut dict = Dictionary::default();
+        while let Some(OpCode::DictEntry(key, value)) = self.opcode() {
+            self.pointer += 1;
+            dict.insert(
+                self.get_value_index(key).unwrap().key()?,
+                self.get_value_index(value).unwrap(),
+            );
+        }
+        self.registers[index.0 as usize] = Placeholder::Value(Value::Dictionary(dict));
+        Ok(())
+    }
+
+    pub fn run_build_string(&mut self, index: RegisterIndex) {
+        self.pointer += 1;
+        let mut string = String::new();
+        while let Some(OpCode::StringPart(part)) = self.opcode() {
+            self.pointer += 1;
+            match self.get_value_index(part).unwrap() {
+                Value::Object(Object::String(s)) => string.push_str(&s),
+                value => string.push_str(&value.to_string()),
             }
+        }
+        self.registers[index.0 as usize] =
+            Placeholder::Value(Value::Object(Object::String(Rc::new(string))));
+    }
+
+    pub fn run_build_module(
+        &mut self,
+        names_index: ValueIndex,
+        index: RegisterIndex,
+    ) -> Result<()> {
+        let names = self.get_value_index(names_index).unwrap().list()?;
+        self.pointer += 1;
+        let mut values = Vector::new();
+        while let Some(OpCode::ListElement(element)) = self.opcode() {
+            self.pointer += 1;
+            values.push_back(self.get_value_index(element).unwrap());
+        }
+        self.registers[index.0 as usize] =
+            Placeholder::Value(Value::Object(Object::Module(Rc::new(Module {
+                names,
+                values,
+            }))));
+        Ok(())
+    }
+
+    pub fn run_create_behaviour(
+        &mut self,
+        methods_index: ValueIndex,
+        index: RegisterIndex,
+    ) -> Result<()> {
+        let methods = self
+            .get_value_index(methods_index)
+            .unwrap()
+            .list()?
+            .iter()
+            .map(|m| match m {
+                Value::Symbol(s) => *s,
+                _ => unreachable!(),
+            })
+            .collect();
+        self.registers[index.0 as usize] = Placeholder::Value(Value::Object(Object::Behaviour(
+            Rc::new(Behaviour::new(methods)),
+        )));
+        self.pointer += 1;
+        Ok(())
+    }
+
+    pub fn run_load_slot(
+        &mut self,
+        module_index: ValueIndex,
+        slot: usize,
+        register_index: RegisterIndex,
+    ) -> Result<()> {
+        let value = match self.get_value_index(module_index).unwrap() {
+            Value::Object(Object::Module(m)) => m.values[slot].clone(),
+            _ => return Err(RuntimeErrorType::NotAModule.into()),
         };
+        self.registers[register_index.0 as usize] = Placeholder::Value(value);
+        self.pointer += 1;
+        Ok(())
+    }
+
+    pub fn run_load_member(
+        &mut self,
+        module_index: ValueIndex,
+        key_index: ValueIndex,
+        register_index: RegisterIndex,
+    ) -> Result<()> {
+        let key = self.get_value_index(key_index).unwrap();
+        let value = match self.get_value_index(module_index).unwrap() {
+            Value::Object(Object::Module(m)) => m.get(&key).cloned(),
+            Value::Dictionary(d) => d.get(&key).cloned(),
+            Value::Object(Object::Behaviour(b)) => match &key {
+                Value::Symbol(method) if b.methods.contains(method) => {
+                    Some(Value::Object(Object::Method(b, *method)))
+                }
+                _ => None,
+            },
+            _ => return Err(RuntimeErrorType::NotAModule.into()),
+        }
+        .ok_or(RuntimeErrorType::MissingKey(key))?;
+        self.registers[register_index.0 as usize] = Placeholder::Value(value);
+        self.pointer += 1;
+        Ok(())
+    }
+
+    pub fn run_extract(
+        &mut self,
+        dict_index: ValueIndex,
+        key_index: ValueIndex,
+        register_index: RegisterIndex,
+    ) -> Result<()> {
+        let dict = self.get_value_index(dict_index).unwrap().dictionary()?;
+        let key = self.get_value_index(key_index).unwrap();
+        let value = dict
+            .get(&key)
+            .ok_or(RuntimeErrorType::MissingKey(key.clone()))?;
+        self.registers[register_index.0 as usize] = Placeholder::Value(value.clone());
+        self.pointer += 1;
+        Ok(())
+    }
+
+    pub fn run_make_box(&mut self, value_index: ValueIndex, register_index: RegisterIndex) {
+        let value = self.get_value_index(value_index).unwrap();
+        self.registers[register_index.0 as usize] =
+            Placeholder::Value(Value::Object(Object::Box(Rc::new(RefCell::new(value)))));
+        self.pointer += 1;
+    }
+
+    pub fn run_dereference(
+        &mut self,
+        value_index: ValueIndex,
+        register_index: RegisterIndex,
+    ) -> Result<()> {
+        let value = self.get_value_index(value_index).unwrap().boxed()?;
+        self.registers[register_index.0 as usize] = Placeholder::Value(value.borrow().clone());
+        self.pointer += 1;
+        Ok(())
+    }
+
+    pub fn run_push_context(&mut self, context_index: ValueIndex) -> Result<()> {
+        let context = self.get_value_index(context_index).unwrap().dictionary()?;
+        let mut merged = self.context();
+        merged.extend(context);
+        self.contexts.push(merged);
+        self.pointer += 1;
+        Ok(())
+    }
+
+    pub fn run_push_launch(&mut self) {
+        let launch = Value::Object(Object::Launch(Rc::new(Launch::default())));
+        let context = self
+            .context()
+            .update(Value::Symbol(InternedSymbol::new("launch")), launch);
+        self.contexts.push(context);
+        self.pointer += 1;
+    }
+
+    pub fn run_spawn(
+        &mut self,
+        function_index: ValueIndex,
+        register_index: RegisterIndex,
+    ) -> Result<()> {
+        let function = self.get_value_index(function_index).unwrap();
+        let child = Rc::new(Coroutine::new(function));
+        self.launch()?.spawn(child.clone());
+        self.registers[register_index.0 as usize] =
+            Placeholder::Value(Value::Object(Object::Coroutine(child)));
+        self.pointer += 1;
+        Ok(())
+    }
+
+    pub fn run_push_handler(&mut self, register: RegisterIndex, offset: isize) {
+        self.handlers.push(Handler {
+            catch: self.pointer.saturating_add_signed(offset),
+            register,
+            contexts: self.contexts.len(),
+        });
+        self.pointer += 1;
+    }
+
+    pub fn run_pop_handler(&mut self) {
+        self.handlers.pop();
+        self.pointer += 1;
+    }
+
+    /// Jump to the innermost handler, giving it the error
+    pub fn catch(&mut self, error: Value) {
+        let handler = self.handlers.pop().unwrap();
+        self.contexts.truncate(handler.contexts);
+        self.inside_call = None;
+        self.registers[usize::from(handler.register)] = Placeholder::Value(error);
+        self.pointer = handler.catch;
+    }
+
+    pub fn run_pop_context(&mut self) {
+        self.contexts.pop();
+        self.pointer += 1;
+    }
+
+    pub fn run_load_context(
+        &mut self,
+        key_index: ValueIndex,
+        register_index: RegisterIndex,
+    ) -> Result<()> {
+        let key = self.get_value_index(key_index).unwrap();
+        let value = self
+            .context()
+            .get(&key)
+            .cloned()
+            .ok_or(RuntimeErrorType::NotInContext(key))?;
+        self.registers[register_index.0 as usize] = Placeholder::Value(value);
+        self.pointer += 1;
+        Ok(())
     }
 }
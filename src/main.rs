@@ -1,47 +1,199 @@
-use crate::{compiler::Compiler, vm::VM};
+use std::{
+    fs,
+    io::{self, Read},
+    process::ExitCode,
+};
 
+use crate::{
+    diagnostic::render,
+    module::compile_source,
+    symbol::InternedSymbol,
+    value::{Dictionary, Object, Value},
+    vm::VM,
+};
+
+mod behaviour;
 mod compiler;
+mod coroutine;
+mod diagnostic;
 mod expression;
 mod frame;
+mod module;
 mod native_function;
+mod number;
 mod opcode;
 mod parser;
+mod repl;
+mod symbol;
 mod tokeniser;
 mod value;
 mod vm;
 
-fn main() {
-    let src = include_str!("programs/hello_world.maxlang");
-    let ts = tokeniser::Token::tokenise_source(src, "")
-        .map(|x| x.unwrap())
-        .collect::<Vec<_>>();
-    let (_, exp) = parser::parse_expression(&ts).unwrap();
-    let mut c = Compiler::new();
-    c.compile_expression(None, &exp, true).unwrap();
-    let f = c.frame_to_function();
-    let mut vm = VM::from_bare_function(f);
-    loop {
-	// println!("{:?}", vm);
-        match vm.step() {
-            Ok(Some(v)) => {
-                println!("GOT A VALUE: {:?}", v);
-                break;
+const USAGE: &str = "usage: maxlang [FILE | -e EXPRESSION | - | repl] [ARGUMENTS...]
+Runs the file, the expression, or the program read from stdin (the default),
+printing its value, or starts an interactive session.
+The arguments are a list of strings in ^$args";
+
+/// Where the program to run comes from
+#[derive(Debug, PartialEq)]
+enum Source {
+    File(String),
+    Expression(String),
+    Stdin,
+    Repl,
+}
+
+/// Split the command line arguments (without the program name)
+/// into the source to run and the arguments to give it
+fn parse_args(mut args: Vec<String>) -> Result<(Source, Vec<String>), String> {
+    if args.is_empty() {
+        return Ok((Source::Stdin, args));
+    }
+    let first = args.remove(0);
+    let source = match first.as_str() {
+        "-" => Source::Stdin,
+        "-e" => {
+            if args.is_empty() {
+                return Err("-e needs an expression to run".into());
             }
-            Err(s) => {
-                println!("{:?}", s);
-                break;
+            Source::Expression(args.remove(0))
+        }
+        "repl" => Source::Repl,
+        "-h" | "--help" => return Err(USAGE.into()),
+        flag if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+        _ => Source::File(first),
+    };
+    Ok((source, args))
+}
+
+/// Read the source, returning it along with the file name to report errors with
+fn read_source(source: Source) -> Result<(String, String), String> {
+    match source {
+        Source::File(path) => fs::read_to_string(&path)
+            .map(|s| (s, path.clone()))
+            .map_err(|e| render(&format!("couldn't read {}: {}", path, e), None)),
+        Source::Expression(e) => Ok((e, "<expression>".into())),
+        Source::Stdin => {
+            let mut s = String::new();
+            io::stdin()
+                .read_to_string(&mut s)
+                .map_err(|e| render(&format!("couldn't read stdin: {}", e), None))?;
+            Ok((s, "<stdin>".into()))
+        }
+        Source::Repl => unreachable!(),
+    }
+}
+
+/// The dynamic context programs are run in, with the arguments as a list of strings
+fn context(args: Vec<String>) -> Dictionary {
+    let args = args
+        .into_iter()
+        .map(|a| Value::Object(Object::String(a.into())))
+        .collect();
+    Dictionary::default().update(
+        Value::Symbol(InternedSymbol::new("args")),
+        Value::List(args),
+    )
+}
+
+/// Tokenise, parse, compile and run the source, with the arguments in the context.
+/// Errors are rendered with the source they happened in
+fn run(source: &str, file: &str, args: Vec<String>) -> Result<Value, String> {
+    let function = compile_source(source, file).map_err(|e| e.render())?;
+    VM::with_context(function, context(args))
+        .run()
+        .map_err(|e| e.render())
+}
+
+fn main() -> ExitCode {
+    let (source, args) = match parse_args(std::env::args().skip(1).collect()) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("{}", message);
+            return ExitCode::from(2);
+        }
+    };
+    if source == Source::Repl {
+        return match repl::run(context(args)) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::FAILURE
             }
-            _ => (),
+        };
+    }
+    let result = read_source(source).and_then(|(source, file)| run(&source, &file, args));
+    match result {
+        Ok(value) => {
+            println!("{}", value);
+            ExitCode::SUCCESS
+        }
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::FAILURE
         }
     }
 }
 
-// fn main() {
-//     let (s, e) = parse_program(include_str!("./fac.maxlang")).unwrap();
-//     let mut c = Compiler::new();
-//     c.compile_expression(None, &e[0], true).unwrap();
-//     let f = c.frame_to_function();
+#[cfg(test)]
+mod test {
+    use crate::value::Value;
+
+    use super::{parse_args, run, Source};
 
-//     let mut vm = VM::from_bare_function(f);
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
 
-// }
+    #[test]
+    fn arguments_choose_the_source() {
+        assert_eq!(parse_args(vec![]), Ok((Source::Stdin, vec![])));
+        assert_eq!(
+            parse_args(args(&["script.maxlang", "-e", "x"])),
+            Ok((Source::File("script.maxlang".into()), args(&["-e", "x"])))
+        );
+        assert_eq!(
+            parse_args(args(&["-e", "1 `+ 2", "a"])),
+            Ok((Source::Expression("1 `+ 2".into()), args(&["a"])))
+        );
+        assert_eq!(
+            parse_args(args(&["-", "a"])),
+            Ok((Source::Stdin, args(&["a"])))
+        );
+        assert_eq!(parse_args(args(&["repl"])), Ok((Source::Repl, vec![])));
+        assert!(parse_args(args(&["-e"])).is_err());
+        assert!(parse_args(args(&["--what"])).is_err());
+    }
+
+    #[test]
+    fn scripts_get_their_arguments() {
+        assert_eq!(
+            run("len ^$args", "", args(&["a", "b"])).map(|v| v.to_string()),
+            Ok("2".into())
+        );
+        assert_eq!(
+            run("^$args", "", args(&["a \"b\""])).map(|v| v.to_string()),
+            Ok(r#"["a \"b\""]"#.into())
+        );
+        assert!(run("(1", "", vec![]).is_err());
+        assert!(run("\"open", "", vec![]).is_err());
+        assert!(run("1 `+ nil", "", vec![]).is_err());
+        assert_eq!(run("[]", "", vec![]), Ok(Value::List(im::vector![])));
+    }
+
+    #[test]
+    fn errors_show_where_they_happened() {
+        let error = |source| run(source, "main.maxlang", vec![]).unwrap_err();
+        assert_eq!(
+            error("let x 1\n\"\\q\""),
+            "error: invalid escape in string
+ --> main.maxlang:2:2
+  |
+2 | \"\\q\"
+  |  ^^"
+        );
+        assert!(error("f x)").starts_with("error: unexpected `)`\n --> main.maxlang:1:4\n"));
+        assert!(error("frob 2").starts_with("error: symbol isn't defined\n --> main.maxlang:1:1\n"));
+        assert!(error("let [a] 1, a").ends_with("1 | let [a] 1, a\n  |     ^^^"));
+    }
+}
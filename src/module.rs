@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    compiler::{Compiler, CompilerError},
+    diagnostic::render_span,
+    expression::LocatedExpression,
+    parser::parse_all,
+    tokeniser::{Span, Token},
+    value::{Function, Value},
+};
+
+#[derive(Debug, Clone)]
+pub enum ModuleError {
+    /// The file couldn't be read
+    Io(PathBuf, String),
+    /// Tokeniser and parse errors borrow the source, so are kept as their message and span
+    Tokeniser(String, Span),
+    Parse(String, Option<Span>),
+    Compiler(CompilerError),
+    /// The chain of imports which leads back to a module that is still being run
+    Cycle(Vec<PathBuf>),
+}
+
+impl Display for ModuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModuleError::Io(path, e) => {
+                f.write_fmt(format_args!("couldn't read {}: {}", path.display(), e))
+            }
+            ModuleError::Tokeniser(message, _) | ModuleError::Parse(message, _) => {
+                f.write_str(message)
+            }
+            ModuleError::Compiler(e) => e.fmt(f),
+            ModuleError::Cycle(chain) => {
+                f.write_str("import cycle: ")?;
+                for (i, path) in chain.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" -> ")?;
+                    }
+                    f.write_fmt(format_args!("{}", path.display()))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ModuleError {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            ModuleError::Tokeniser(_, span) => Some(span.clone()),
+            ModuleError::Parse(_, span) => span.clone(),
+            ModuleError::Compiler(e) => e.span.clone(),
+            ModuleError::Io(_, _) | ModuleError::Cycle(_) => None,
+        }
+    }
+
+    /// The error with the line of source it's on, the way rustc shows errors
+    pub fn render(&self) -> String {
+        render_span(&self.to_string(), self.span().as_ref())
+    }
+}
+
+/// Tokenise the whole source, remembering it so that errors can show it later
+pub fn tokenise<'a>(source: &'a Rc<str>, file: &'a str) -> Result<Vec<Token<'a>>, ModuleError> {
+    Token::tokenise_source(source, file)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ModuleError::Tokeniser(e.to_string(), (&e.location).into()))
+}
+
+/// Parse the tokens as a single expression, failing if any are left over
+pub fn parse<'a>(tokens: &'a [Token<'a>]) -> Result<LocatedExpression<'a>, ModuleError> {
+    parse_all(tokens).map_err(|e| ModuleError::Parse(e.to_string(), e.span()))
+}
+
+/// Tokenise, parse and compile a whole source file into a function taking no arguments
+pub fn compile_source(source: &str, file: &str) -> Result<Function, ModuleError> {
+    let source = source.into();
+    let tokens = tokenise(&source, file)?;
+    let expression = parse(&tokens)?;
+    let mut compiler = Compiler::new();
+    compiler
+        .compile_expression(None, &expression, true)
+        .map_err(ModuleError::Compiler)?;
+    Ok(compiler.frame_to_function())
+}
+
+/// Keeps track of the modules which have been imported, so each is only run once
+#[derive(Debug, Default)]
+pub struct Modules {
+    cache: HashMap<PathBuf, Value>,
+    /// The modules which are currently being run, in the order they were imported
+    loading: Vec<PathBuf>,
+}
+
+impl Modules {
+    pub fn cached(&self, path: &Path) -> Option<Value> {
+        self.cache.get(path).cloned()
+    }
+
+    /// Mark the module as being run, failing if it is already being run
+    pub fn start_loading(&mut self, path: &Path) -> Result<(), ModuleError> {
+        if let Some(i) = self.loading.iter().position(|p| p == path) {
+            let mut chain = self.loading[i..].to_vec();
+            chain.push(path.into());
+            return Err(ModuleError::Cycle(chain));
+        }
+        self.loading.push(path.into());
+        Ok(())
+    }
+
+    /// Mark the module as finished, caching its value if it ran successfully
+    pub fn finish_loading(&mut self, path: &Path, value: Option<Value>) {
+        self.loading.retain(|p| p != path);
+        if let Some(value) = value {
+            self.cache.insert(path.into(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use crate::{
+        value::Value,
+        vm::{RuntimeError, RuntimeErrorType, VM},
+    };
+
+    use super::ModuleError;
+
+    /// A directory of files for a test, which is removed when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn join(&self, path: &str) -> PathBuf {
+            self.0.join(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Write the files into a new directory
+    fn write_files(name: &str, files: &[(&str, &str)]) -> TempDir {
+        let dir =
+            TempDir(std::env::temp_dir().join(format!("maxlang-{}-{}", name, std::process::id())));
+        for (path, source) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        dir
+    }
+
+    fn run_file(path: PathBuf) -> Result<Value, RuntimeError> {
+        VM::default().import(&path)
+    }
+
+    #[test]
+    fn imports_are_relative_to_the_importing_file() {
+        let dir = write_files(
+            "relative",
+            &[
+                ("main.maxlang", "$x (import \"lib/a.maxlang\")"),
+                ("lib/a.maxlang", "<$x: import \"b.maxlang\">"),
+                ("lib/b.maxlang", "3"),
+            ],
+        );
+        assert_eq!(run_file(dir.join("main.maxlang")).unwrap(), Value::Int(3));
+    }
+
+    #[test]
+    fn modules_are_only_run_once() {
+        let dir = write_files(
+            "cache",
+            &[
+                (
+                    "main.maxlang",
+                    "import \"box.maxlang\" `= import \"box.maxlang\"",
+                ),
+                ("box.maxlang", "&1"),
+            ],
+        );
+        assert_eq!(
+            run_file(dir.join("main.maxlang")).unwrap(),
+            Value::Bool(true)
+        );
+    }
+
+    #[test]
+    fn errors_in_imported_modules_are_in_their_file() {
+        let dir = write_files(
+            "located",
+            &[
+                ("main.maxlang", "import \"lib.maxlang\""),
+                ("lib.maxlang", "\n(1 `+ nil)"),
+            ],
+        );
+        let error = run_file(dir.join("main.maxlang")).unwrap_err();
+        let span = error.span.as_ref().unwrap();
+        assert!(span.file.ends_with("lib.maxlang"));
+        assert_eq!((span.start_pos, span.end_pos), (2, 10));
+        assert!(error.render().contains("lib.maxlang:2:2\n"));
+    }
+
+    #[test]
+    fn import_cycles_are_errors() {
+        let dir = write_files(
+            "cycle",
+            &[
+                ("a.maxlang", "import \"b.maxlang\""),
+                ("b.maxlang", "import \"a.maxlang\""),
+            ],
+        );
+        match run_file(dir.join("a.maxlang")) {
+            Err(RuntimeError {
+                error_type: RuntimeErrorType::ModuleError(e @ ModuleError::Cycle(_)),
+                ..
+            }) => {
+                assert!(e.to_string().starts_with("import cycle: "));
+                assert!(e.to_string().ends_with("a.maxlang"));
+            }
+            r => panic!("expected an import cycle, got {:?}", r),
+        }
+    }
+}
@@ -1,12 +1,16 @@
-use std::rc::Rc;
+use std::{cmp::Ordering, rc::Rc};
 
 use crate::{
+    behaviour::{Builtin, Builtins},
+    coroutine::Coroutine,
     expression::Symbol,
-    value::{Closure, ClosureType, Object, Placeholder, Value},
-    vm::RuntimeError,
+    number,
+    symbol::InternedSymbol,
+    value::{Closure, ClosureType, Object, Value, ValueError},
+    vm::{RuntimeError, RuntimeErrorType},
 };
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum NativeFunction {
     LessThan,
     GreaterThan,
@@ -21,6 +25,28 @@ pub enum NativeFunction {
     Index,
     Push,
     Set,
+    Get,
+    Insert,
+    Remove,
+    Keys,
+    Values,
+    Length,
+    Put,
+    Implement,
+    Type,
+    Generator,
+    Next,
+    Send,
+    Done,
+    Div,
+    Mod,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    Int,
+    Float,
 }
 
 impl NativeFunction {
@@ -39,6 +65,28 @@ impl NativeFunction {
             "ind" => Some(Self::Index),
             "push" => Some(Self::Push),
             "set" => Some(Self::Set),
+            "get" => Some(Self::Get),
+            "insert" => Some(Self::Insert),
+            "remove" => Some(Self::Remove),
+            "keys" => Some(Self::Keys),
+            "values" => Some(Self::Values),
+            "len" => Some(Self::Length),
+            "put" => Some(Self::Put),
+            "implement" => Some(Self::Implement),
+            "type" => Some(Self::Type),
+            "generator" => Some(Self::Generator),
+            "next" => Some(Self::Next),
+            "send" => Some(Self::Send),
+            "done" => Some(Self::Done),
+            "div" => Some(Self::Div),
+            "mod" => Some(Self::Mod),
+            "band" => Some(Self::BitAnd),
+            "bor" => Some(Self::BitOr),
+            "bxor" => Some(Self::BitXor),
+            "shl" => Some(Self::ShiftLeft),
+            "shr" => Some(Self::ShiftRight),
+            "int" => Some(Self::Int),
+            "float" => Some(Self::Float),
             _ => None,
         }
     }
@@ -46,7 +94,7 @@ impl NativeFunction {
     /// The number of arguments
     pub fn arguments(&self) -> usize {
         match self {
-            Self::Set => 3,
+            Self::Set | Self::Insert | Self::Implement => 3,
             NativeFunction::LessThan
             | Self::GreaterThan
             | NativeFunction::Difference
@@ -57,36 +105,92 @@ impl NativeFunction {
             | NativeFunction::Quotient
             | NativeFunction::Sum
             | Self::Index
-            | Self::Push => 2,
-            Self::Print => 1,
+            | Self::Push
+            | Self::Get
+            | Self::Remove
+            | Self::Put
+            | Self::Send
+            | Self::Div
+            | Self::Mod
+            | Self::BitAnd
+            | Self::BitOr
+            | Self::BitXor
+            | Self::ShiftLeft
+            | Self::ShiftRight => 2,
+            Self::Print
+            | Self::Keys
+            | Self::Values
+            | Self::Length
+            | Self::Type
+            | Self::Generator
+            | Self::Next
+            | Self::Done
+            | Self::Int
+            | Self::Float => 1,
         }
     }
 
+    /// The user's implementation of this function for the type of its first argument,
+    /// for the natives which dispatch through a builtin behaviour
+    pub fn implementation(&self, builtins: &Builtins, first: &Value) -> Option<Value> {
+        let (behaviour, method) = match self {
+            NativeFunction::Equal => (Builtin::Eq, "="),
+            NativeFunction::Print => (Builtin::Show, "print"),
+            NativeFunction::LessThan => (Builtin::Ord, "lt"),
+            NativeFunction::LessThanEqual => (Builtin::Ord, "lte"),
+            NativeFunction::GreaterThan => (Builtin::Ord, "gt"),
+            NativeFunction::GreaterThanEqual => (Builtin::Ord, "gte"),
+            _ => return None,
+        };
+        builtins
+            .get(behaviour)
+            .find(InternedSymbol::new(method), first)
+    }
+
     pub fn call(&self, args: Vec<Value>) -> std::result::Result<Value, RuntimeError> {
         match self {
-            NativeFunction::LessThan => Ok(Value::Bool(args[0].number()? < args[1].number()?)),
-            NativeFunction::Sum => Ok(Value::Number(args[0].number()? + args[1].number()?)),
-            NativeFunction::Difference => Ok(Value::Number(args[0].number()? - args[1].number()?)),
-            NativeFunction::GreaterThan => Ok(Value::Bool(args[0].number()? > args[1].number()?)),
+            NativeFunction::LessThan => Ok(Value::Bool(
+                number::compare(&args[0], &args[1])? == Some(Ordering::Less),
+            )),
+            NativeFunction::Sum => Ok(number::sum(&args[0], &args[1])?),
+            NativeFunction::Difference => Ok(number::difference(&args[0], &args[1])?),
+            NativeFunction::GreaterThan => Ok(Value::Bool(
+                number::compare(&args[0], &args[1])? == Some(Ordering::Greater),
+            )),
             NativeFunction::Equal => Ok(Value::Bool(args[0] == args[1])),
-            NativeFunction::GreaterThanEqual => {
-                Ok(Value::Bool(args[0].number()? >= args[1].number()?))
-            }
-            NativeFunction::LessThanEqual => {
-                Ok(Value::Bool(args[0].number()? <= args[1].number()?))
-            }
-            NativeFunction::Multiply => Ok(Value::Number(args[0].number()? * args[1].number()?)),
-            NativeFunction::Quotient => Ok(Value::Number(args[0].number()? / args[1].number()?)),
+            NativeFunction::GreaterThanEqual => Ok(Value::Bool(matches!(
+                number::compare(&args[0], &args[1])?,
+                Some(Ordering::Greater | Ordering::Equal)
+            ))),
+            NativeFunction::LessThanEqual => Ok(Value::Bool(matches!(
+                number::compare(&args[0], &args[1])?,
+                Some(Ordering::Less | Ordering::Equal)
+            ))),
+            NativeFunction::Multiply => Ok(number::product(&args[0], &args[1])?),
+            NativeFunction::Quotient => Ok(number::quotient(&args[0], &args[1])?),
+            NativeFunction::Div => Ok(number::div(&args[0], &args[1])?),
+            NativeFunction::Mod => Ok(number::modulo(&args[0], &args[1])?),
+            NativeFunction::BitAnd => Ok(number::bit_and(&args[0], &args[1])?),
+            NativeFunction::BitOr => Ok(number::bit_or(&args[0], &args[1])?),
+            NativeFunction::BitXor => Ok(number::bit_xor(&args[0], &args[1])?),
+            NativeFunction::ShiftLeft => Ok(number::shift_left(&args[0], &args[1])?),
+            NativeFunction::ShiftRight => Ok(number::shift_right(&args[0], &args[1])?),
+            NativeFunction::Int => Ok(number::to_int(&args[0])?),
+            NativeFunction::Float => Ok(Value::Float(number::to_float(&args[0])?)),
             NativeFunction::Print => {
                 match args[0].clone() {
                     Value::Object(Object::String(s)) => println!("{}", s),
-                    x => println!("{:?}", x),
+                    x => println!("{}", x),
                 }
                 Ok(args[0].clone())
             }
             NativeFunction::Index => {
-                let l = args[0].list()?;
-                Ok(l[args[1].number()? as usize].clone())
+                let list = args[0].list()?;
+                let index = args[1].int()?;
+                usize::try_from(index)
+                    .ok()
+                    .and_then(|i| list.get(i).cloned())
+                    .ok_or_else(|| RuntimeErrorType::MissingIndex(index).into())
             }
             NativeFunction::Push => {
                 let mut l = args[0].list()?.clone();
@@ -95,21 +199,77 @@ impl NativeFunction {
             }
             NativeFunction::Set => {
                 let mut l = args[0].list()?.clone();
-                l.set(args[1].number()? as usize, args[2].clone());
+                let index = args[1].int()?;
+                match usize::try_from(index) {
+                    Ok(i) if i < l.len() => l.set(i, args[2].clone()),
+                    _ => return Err(RuntimeErrorType::MissingIndex(index).into()),
+                };
                 Ok(Value::List(l))
             }
+            NativeFunction::Get => Ok(args[0]
+                .dictionary()?
+                .get(&args[1])
+                .cloned()
+                .unwrap_or(Value::Nil)),
+            NativeFunction::Insert => Ok(Value::Dictionary(
+                args[0]
+                    .dictionary()?
+                    .update(args[1].key()?, args[2].clone()),
+            )),
+            NativeFunction::Remove => {
+                Ok(Value::Dictionary(args[0].dictionary()?.without(&args[1])))
+            }
+            NativeFunction::Keys => {
+                Ok(Value::List(args[0].dictionary()?.keys().cloned().collect()))
+            }
+            NativeFunction::Values => Ok(Value::List(
+                args[0].dictionary()?.values().cloned().collect(),
+            )),
+            NativeFunction::Put => {
+                args[0].boxed()?.replace(args[1].clone());
+                Ok(args[1].clone())
+            }
+            NativeFunction::Implement => {
+                let behaviour = match &args[0] {
+                    Value::Object(Object::Behaviour(b)) => b,
+                    _ => return Err(ValueError::NotABehaviour.into()),
+                };
+                let methods = match &args[2] {
+                    Value::Object(Object::Module(m)) => m
+                        .names
+                        .iter()
+                        .cloned()
+                        .zip(m.values.iter().cloned())
+                        .collect(),
+                    d => d.dictionary()?,
+                };
+                behaviour.implement(args[1].clone(), methods)?;
+                Ok(args[0].clone())
+            }
+            NativeFunction::Type => Ok(args[0].type_of()),
+            NativeFunction::Generator => Ok(Value::Object(Object::Coroutine(Rc::new(
+                Coroutine::new(args[0].clone()),
+            )))),
+            NativeFunction::Done => Ok(Value::Bool(args[0].coroutine()?.is_finished())),
+            // Resuming a coroutine moves its frames onto the VM's stack, so the VM does it
+            NativeFunction::Next | NativeFunction::Send => {
+                Err(RuntimeErrorType::ResumedOutsideVM.into())
+            }
+            NativeFunction::Length => Ok(Value::Int(match &args[0] {
+                Value::List(l) => l.len(),
+                Value::Dictionary(d) => d.len(),
+                Value::Object(Object::String(s)) => s.chars().count(),
+                _ => return Err(ValueError::NotACollection.into()),
+            } as i64)),
         }
     }
 
-    pub fn call_or_curry(
-        &self,
-        args: Vec<Value>,
-    ) -> std::result::Result<Placeholder, RuntimeError> {
+    pub fn call_or_curry(&self, args: Vec<Value>) -> std::result::Result<Value, RuntimeError> {
         Ok(match args.len().cmp(&self.arguments()) {
-            std::cmp::Ordering::Less => Placeholder::Value(Value::Object(Object::Closure(
-                Rc::new(self.to_closure(args)),
-            ))),
-            std::cmp::Ordering::Equal => Placeholder::Value(self.call(args)?),
+            std::cmp::Ordering::Less => {
+                Value::Object(Object::Closure(Rc::new(self.to_closure(args))))
+            }
+            std::cmp::Ordering::Equal => self.call(args)?,
             std::cmp::Ordering::Greater => unreachable!(),
         })
     }
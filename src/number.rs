@@ -0,0 +1,339 @@
+use std::{cmp::Ordering, rc::Rc};
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{Euclid, FromPrimitive, ToPrimitive, Zero};
+
+use crate::value::{Value, ValueError};
+
+type Result<T> = std::result::Result<T, ValueError>;
+
+/// Two numbers converted to the same type. Numbers are promoted up the tower
+/// int -> bigint -> rational -> float, to the type of whichever is higher
+enum Promoted {
+    Ints(i64, i64),
+    BigInts(BigInt, BigInt),
+    Rationals(BigRational, BigRational),
+    Floats(f64, f64),
+}
+
+/// The position of the number's type in the tower
+fn rank(value: &Value) -> Result<u8> {
+    match value {
+        Value::Int(_) => Ok(0),
+        Value::BigInt(_) => Ok(1),
+        Value::Rational(_) => Ok(2),
+        Value::Float(_) => Ok(3),
+        _ => Err(ValueError::NotANumber),
+    }
+}
+
+fn promote(a: &Value, b: &Value) -> Result<Promoted> {
+    Ok(match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Promoted::Ints(*a, *b),
+        _ => match rank(a)?.max(rank(b)?) {
+            1 => Promoted::BigInts(to_integer(a)?, to_integer(b)?),
+            2 => Promoted::Rationals(to_rational(a)?, to_rational(b)?),
+            _ => Promoted::Floats(to_float(a)?, to_float(b)?),
+        },
+    })
+}
+
+/// Ints and bigints as a bigint
+fn to_integer(value: &Value) -> Result<BigInt> {
+    match value {
+        Value::Int(i) => Ok(BigInt::from(*i)),
+        Value::BigInt(i) => Ok(i.as_ref().clone()),
+        Value::Rational(_) | Value::Float(_) => Err(ValueError::NotAnInt),
+        _ => Err(ValueError::NotANumber),
+    }
+}
+
+/// Any exact number as a rational
+fn to_rational(value: &Value) -> Result<BigRational> {
+    match value {
+        Value::Rational(r) => Ok(r.as_ref().clone()),
+        Value::Float(_) => Err(ValueError::NotAnInt),
+        _ => Ok(BigRational::from_integer(to_integer(value)?)),
+    }
+}
+
+/// Bigints which fit into an int are always stored as ints,
+/// so that equal numbers are always equal values
+pub fn from_integer(i: BigInt) -> Value {
+    match i.to_i64() {
+        Some(i) => Value::Int(i),
+        None => Value::BigInt(Rc::new(i)),
+    }
+}
+
+/// Rationals which are whole numbers are stored as integers
+pub fn from_rational(r: BigRational) -> Value {
+    if r.is_integer() {
+        from_integer(r.to_integer())
+    } else {
+        Value::Rational(Rc::new(r))
+    }
+}
+
+/// The number as a float, which may lose precision
+pub fn to_float(value: &Value) -> Result<f64> {
+    match value {
+        Value::Int(i) => Ok(*i as f64),
+        Value::BigInt(i) => Ok(i.to_f64().unwrap_or(f64::NAN)),
+        Value::Rational(r) => Ok(r.to_f64().unwrap_or(f64::NAN)),
+        Value::Float(f) => Ok(*f),
+        _ => Err(ValueError::NotANumber),
+    }
+}
+
+/// Apply the operation for the type the numbers are promoted to.
+/// Ints which overflow are redone as bigints
+fn arithmetic(
+    a: &Value,
+    b: &Value,
+    int: fn(i64, i64) -> Option<i64>,
+    integer: fn(BigInt, BigInt) -> BigInt,
+    rational: fn(BigRational, BigRational) -> BigRational,
+    float: fn(f64, f64) -> f64,
+) -> Result<Value> {
+    Ok(match promote(a, b)? {
+        Promoted::Ints(a, b) => match int(a, b) {
+            Some(i) => Value::Int(i),
+            None => from_integer(integer(a.into(), b.into())),
+        },
+        Promoted::BigInts(a, b) => from_integer(integer(a, b)),
+        Promoted::Rationals(a, b) => from_rational(rational(a, b)),
+        Promoted::Floats(a, b) => Value::Float(float(a, b)),
+    })
+}
+
+pub fn sum(a: &Value, b: &Value) -> Result<Value> {
+    arithmetic(
+        a,
+        b,
+        i64::checked_add,
+        |a, b| a + b,
+        |a, b| a + b,
+        |a, b| a + b,
+    )
+}
+
+pub fn difference(a: &Value, b: &Value) -> Result<Value> {
+    arithmetic(
+        a,
+        b,
+        i64::checked_sub,
+        |a, b| a - b,
+        |a, b| a - b,
+        |a, b| a - b,
+    )
+}
+
+pub fn product(a: &Value, b: &Value) -> Result<Value> {
+    arithmetic(
+        a,
+        b,
+        i64::checked_mul,
+        |a, b| a * b,
+        |a, b| a * b,
+        |a, b| a * b,
+    )
+}
+
+/// Dividing exact numbers gives an exact rational. `div` gives an integer
+pub fn quotient(a: &Value, b: &Value) -> Result<Value> {
+    if let Promoted::Floats(a, b) = promote(a, b)? {
+        return Ok(Value::Float(a / b));
+    }
+    let divisor = to_rational(b)?;
+    if divisor.is_zero() {
+        return Err(ValueError::DivisionByZero);
+    }
+    Ok(from_rational(to_rational(a)? / divisor))
+}
+
+/// None if either is NaN
+pub fn compare(a: &Value, b: &Value) -> Result<Option<Ordering>> {
+    Ok(match promote(a, b)? {
+        Promoted::Ints(a, b) => Some(a.cmp(&b)),
+        Promoted::BigInts(a, b) => Some(a.cmp(&b)),
+        Promoted::Rationals(a, b) => Some(a.cmp(&b)),
+        Promoted::Floats(a, b) => a.partial_cmp(&b),
+    })
+}
+
+/// Apply an operation which only makes sense for integers,
+/// trying it on ints before falling back to bigints
+fn integer(
+    a: &Value,
+    b: &Value,
+    int: fn(i64, i64) -> Option<i64>,
+    integer: fn(BigInt, BigInt) -> Option<BigInt>,
+) -> Result<Value> {
+    if let (Value::Int(a), Value::Int(b)) = (a, b) {
+        if let Some(i) = int(*a, *b) {
+            return Ok(Value::Int(i));
+        }
+    }
+    integer(to_integer(a)?, to_integer(b)?)
+        .map(from_integer)
+        .ok_or(ValueError::Overflow)
+}
+
+/// Euclidean division, so that `mod` is never negative
+pub fn div(a: &Value, b: &Value) -> Result<Value> {
+    if to_integer(b)?.is_zero() {
+        return Err(ValueError::DivisionByZero);
+    }
+    integer(a, b, i64::checked_div_euclid, |a, b| Some(a.div_euclid(&b)))
+}
+
+pub fn modulo(a: &Value, b: &Value) -> Result<Value> {
+    if to_integer(b)?.is_zero() {
+        return Err(ValueError::DivisionByZero);
+    }
+    integer(a, b, i64::checked_rem_euclid, |a, b| Some(a.rem_euclid(&b)))
+}
+
+pub fn bit_and(a: &Value, b: &Value) -> Result<Value> {
+    integer(a, b, |a, b| Some(a & b), |a, b| Some(a & b))
+}
+
+pub fn bit_or(a: &Value, b: &Value) -> Result<Value> {
+    integer(a, b, |a, b| Some(a | b), |a, b| Some(a | b))
+}
+
+pub fn bit_xor(a: &Value, b: &Value) -> Result<Value> {
+    integer(a, b, |a, b| Some(a ^ b), |a, b| Some(a ^ b))
+}
+
+/// The most bits a left shift can make a bigint, so a shift can't exhaust memory
+const MAX_SHIFTED_BITS: u64 = 1 << 20;
+
+/// Ints which would lose set bits become bigints.
+/// Overflows if the shift is negative or would give more than `MAX_SHIFTED_BITS` bits
+pub fn shift_left(a: &Value, b: &Value) -> Result<Value> {
+    integer(
+        a,
+        b,
+        |a, b| {
+            let b = u32::try_from(b).ok()?;
+            a.checked_shl(b).filter(|shifted| shifted >> b == a)
+        },
+        |a, b| {
+            let b = u64::try_from(b).ok()?;
+            (a.bits() + b <= MAX_SHIFTED_BITS).then(|| a << b)
+        },
+    )
+}
+
+pub fn shift_right(a: &Value, b: &Value) -> Result<Value> {
+    integer(
+        a,
+        b,
+        |a, b| a.checked_shr(u32::try_from(b).ok()?),
+        |a, b| Some(a >> u32::try_from(b).ok()?),
+    )
+}
+
+/// Convert to an integer, rounding towards zero
+pub fn to_int(a: &Value) -> Result<Value> {
+    match a {
+        Value::Int(_) | Value::BigInt(_) => Ok(a.clone()),
+        Value::Rational(r) => Ok(from_integer(r.to_integer())),
+        Value::Float(f) => BigInt::from_f64(f.trunc())
+            .map(from_integer)
+            .ok_or(ValueError::Overflow),
+        _ => Err(ValueError::NotANumber),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::Ordering;
+
+    use num_bigint::BigInt;
+
+    use crate::value::{Value, ValueError};
+
+    use super::{
+        compare, difference, div, from_integer, modulo, quotient, shift_left, sum, to_int,
+    };
+
+    #[test]
+    fn ints_only_become_floats_when_mixed_with_them() {
+        assert_eq!(sum(&Value::Int(1), &Value::Int(2)).unwrap(), Value::Int(3));
+        assert_eq!(
+            sum(&Value::Int(1), &Value::Float(0.5)).unwrap(),
+            Value::Float(1.5)
+        );
+        assert_eq!(
+            compare(&Value::Int(2), &Value::Float(2.5)).unwrap(),
+            Some(Ordering::Less)
+        );
+    }
+
+    #[test]
+    fn overflowing_ints_become_bigints() {
+        let big = sum(&Value::Int(i64::MAX), &Value::Int(1)).unwrap();
+        assert_eq!(big, from_integer(BigInt::from(i64::MAX) + 1));
+        assert!(matches!(big, Value::BigInt(_)));
+        assert_eq!(
+            shift_left(&Value::Int(1), &Value::Int(64)).unwrap(),
+            from_integer(BigInt::from(1) << 64)
+        );
+        // Bigints which fit are turned back into ints
+        assert_eq!(
+            difference(&big, &Value::Int(1)).unwrap(),
+            Value::Int(i64::MAX)
+        );
+    }
+
+    #[test]
+    fn dividing_integers_is_exact() {
+        let third = quotient(&Value::Int(1), &Value::Int(3)).unwrap();
+        assert_eq!(format!("{:?}", third), "1/3");
+        assert_eq!(
+            sum(&third, &quotient(&Value::Int(2), &Value::Int(3)).unwrap()).unwrap(),
+            Value::Int(1)
+        );
+        assert_eq!(
+            compare(&third, &Value::Float(0.3)).unwrap(),
+            Some(Ordering::Greater)
+        );
+        assert!(matches!(
+            quotient(&Value::Int(1), &Value::Int(0)),
+            Err(ValueError::DivisionByZero)
+        ));
+    }
+
+    #[test]
+    fn integer_operations_are_checked() {
+        assert!(matches!(
+            shift_left(&Value::Int(1), &Value::Int(-1)),
+            Err(ValueError::Overflow)
+        ));
+        assert!(matches!(
+            shift_left(&Value::Int(1), &Value::Int(1 << 30)),
+            Err(ValueError::Overflow)
+        ));
+        assert!(matches!(
+            div(&Value::Int(1), &Value::Int(0)),
+            Err(ValueError::DivisionByZero)
+        ));
+        assert!(matches!(
+            div(&Value::Float(1.0), &Value::Int(1)),
+            Err(ValueError::NotAnInt)
+        ));
+        assert_eq!(
+            modulo(&Value::Int(-7), &Value::Int(3)).unwrap(),
+            Value::Int(2)
+        );
+        assert_eq!(
+            div(&Value::Int(-7), &Value::Int(3)).unwrap(),
+            Value::Int(-3)
+        );
+        assert_eq!(to_int(&Value::Float(-2.7)).unwrap(), Value::Int(-2));
+    }
+}
@@ -1,6 +1,6 @@
 use std::fmt::Debug;
 
-use crate::native_function::NativeFunction;
+use crate::{behaviour::Builtin, native_function::NativeFunction};
 
 pub type VecOffset = i16;
 pub type VecIndex = u8;
@@ -48,6 +48,15 @@ impl From<FunctionIndex> for usize {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanIndex(pub VecIndex);
+
+impl From<SpanIndex> for usize {
+    fn from(value: SpanIndex) -> Self {
+        value.0 as usize
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum OpCode {
     /// Call the function in register .0,
@@ -68,10 +77,23 @@ pub enum OpCode {
     Jump(VecOffset),
     /// Check the boolean in .0, if false, jump by the given offset, otherwise continue
     JumpToPositionIfFalse(ValueIndex, VecOffset),
+    /// Jump by the given offset if the values in .0 and .1 aren't equal, otherwise continue
+    JumpIfNotEqual(ValueIndex, ValueIndex, VecOffset),
+    /// Jump by the given offset unless .0 is a list of length .1
+    /// (or at least .1 if .2 is true), otherwise continue
+    JumpIfNotList(ValueIndex, VecIndex, bool, VecOffset),
+    /// Look up the key in .1 in the dictionary in .0, putting the value into .2.
+    /// Jump by the given offset if .0 isn't a dictionary or doesn't have the key
+    JumpIfMissingKey(ValueIndex, ValueIndex, RegisterIndex, VecOffset),
+    /// Copy the element at index .1 of the list in .0 into .2
+    IndexList(ValueIndex, VecIndex, RegisterIndex),
+    /// Put the list in .0 without its first .1 elements into .2
+    ListTail(ValueIndex, VecIndex, RegisterIndex),
     /// Copy the value from 0 to 1
     CopyValue(ValueIndex, RegisterIndex),
 
     /// Free the value at the given register
+    #[allow(dead_code)]
     CloseValue(RegisterIndex),
     /// Create closure. Takes the index of the function in the current chunk,
     /// puts the result in the register .1
@@ -81,6 +103,68 @@ pub enum OpCode {
     CaptureValue(ValueIndex),
     /// Unconditional crash
     Crash,
+    /// Fail because the value in .0 didn't match the pattern at the span .1
+    MatchFailed(ValueIndex, SpanIndex),
     /// Insert this native function into the given register
     InsertNativeFunction(NativeFunction, RegisterIndex),
+    /// Insert the running program's builtin behaviour into the given register
+    InsertBuiltin(Builtin, RegisterIndex),
+    /// Build a list into the given register.
+    /// Followed by `ListElement`s
+    BuildList(RegisterIndex),
+    /// A value to push onto the end of the list being built
+    ListElement(ValueIndex),
+    /// Build a dictionary into the given register.
+    /// Followed by `DictEntry`s
+    BuildDict(RegisterIndex),
+    /// A key (.0) and value (.1) to insert into the dictionary being built
+    DictEntry(ValueIndex, ValueIndex),
+    /// Build a string into the given register.
+    /// Followed by `StringPart`s
+    BuildString(RegisterIndex),
+    /// A value to append to the string being built
+    StringPart(ValueIndex),
+    /// Build a module with the list of member names in .0 into the register .1.
+    /// Followed by a `ListElement` for each member's value
+    BuildModule(ValueIndex, RegisterIndex),
+    /// Copy the member in slot .1 of the module in .0 into .2
+    LoadSlot(ValueIndex, VecIndex, RegisterIndex),
+    /// Look up the member named by the symbol in .1 in the module or dictionary in .0,
+    /// putting the value into .2. Used when the layout isn't known at compile time
+    LoadMember(ValueIndex, ValueIndex, RegisterIndex),
+    /// Look up the key in .1 in the dictionary in .0, putting the value into .2.
+    /// Crashes if the key isn't in the dictionary
+    Extract(ValueIndex, ValueIndex, RegisterIndex),
+    /// Merge the dictionary in .0 into the current context, and make it the new context
+    PushContext(ValueIndex),
+    /// Go back to the context before the last `PushContext`
+    PopContext,
+    /// Look up the key in .0 in the current context, putting the value into .1
+    LoadContext(ValueIndex, RegisterIndex),
+    /// Create a new behaviour with the list of method names in .0, putting it into .1
+    CreateBehaviour(ValueIndex, RegisterIndex),
+    /// Run the module at the path in .0 (or get it from the cache), putting its value into .1
+    Import(ValueIndex, RegisterIndex),
+    /// Hand the value in .0 to whatever resumed the running coroutine, suspending it.
+    /// The value the coroutine is next resumed with goes into .1
+    Yield(ValueIndex, RegisterIndex),
+    /// Create a coroutine running the function in .0 as a child of the innermost
+    /// launch scope, putting it into .1
+    Spawn(ValueIndex, RegisterIndex),
+    /// Start a new launch scope, binding it to `launch` in a new dynamic context
+    PushLaunch,
+    /// Resume the children of the innermost launch scope in turn, only
+    /// continuing once they have all finished
+    FinishLaunch,
+    /// Until the matching `PopHandler`, catch errors by jumping by the offset
+    /// with the error put into .0
+    PushHandler(RegisterIndex, VecOffset),
+    /// Stop catching errors with the last handler pushed
+    PopHandler,
+    /// Unwind to the innermost handler with the value in .0
+    Raise(ValueIndex),
+    /// Put the value in .0 into a new box, stored in .1
+    MakeBox(ValueIndex, RegisterIndex),
+    /// Copy the value inside the box in .0 into .1
+    Dereference(ValueIndex, RegisterIndex),
 }
@@ -1,6 +1,10 @@
+use std::fmt::Display;
+
 use crate::{
-    expression::{Block, Expression, Let, Literal, LocatedExpression, Symbol},
-    tokeniser::{Location, Token, TokenData},
+    expression::{
+        Block, Expression, Let, Literal, LocatedExpression, LocatedPattern, Pattern, Symbol,
+    },
+    tokeniser::{Location, Span, Token, TokenData},
 };
 
 #[derive(Debug, PartialEq)]
@@ -10,8 +14,10 @@ pub enum ParseErrorType<'a> {
     CouldNotMatchSymbol,
     CouldNotMatchNumber,
     CouldNotMatchString,
-    IncompleteInfix,
-    NoArgumentsToCall,
+    /// A literal in a pattern has to be known at compile time
+    NonConstantPattern,
+    /// A token was left over after the whole expression
+    UnexpectedToken,
 }
 
 impl<'a> ParseErrorType<'a> {
@@ -38,20 +44,75 @@ impl<'a> From<ParseErrorType<'a>> for ParseError<'a> {
     }
 }
 
+impl<'a> Display for ParseError<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.error_type, &self.location) {
+            (ParseErrorType::NoMoreTokens, _) => f.write_str("unexpected end of input"),
+            // Whichever token was expected, the one found couldn't start anything here
+            (ParseErrorType::TokenDoesntMatch(_) | ParseErrorType::UnexpectedToken, Some(l)) => f
+                .write_fmt(format_args!(
+                    "unexpected `{}`",
+                    &l.source[l.start_pos..l.end_pos]
+                )),
+            (ParseErrorType::TokenDoesntMatch(expected), None) => {
+                f.write_fmt(format_args!("expected {:?}", expected))
+            }
+            (ParseErrorType::UnexpectedToken, None) => f.write_str("unexpected token"),
+            (ParseErrorType::CouldNotMatchSymbol, _) => f.write_str("expected a symbol"),
+            (ParseErrorType::CouldNotMatchNumber, _) => f.write_str("expected a number"),
+            (ParseErrorType::CouldNotMatchString, _) => f.write_str("expected a string"),
+            (ParseErrorType::NonConstantPattern, _) => {
+                f.write_str("patterns can only contain constant literals")
+            }
+        }
+    }
+}
+
+impl<'a> ParseError<'a> {
+    pub fn span(&self) -> Option<Span> {
+        self.location.as_ref().map(Span::from)
+    }
+
+    /// How far into the tokens the error is. Running out of them is further than any
+    /// located error, and other errors without a location are at the start
+    fn position(&self) -> usize {
+        match (&self.error_type, &self.location) {
+            (_, Some(location)) => location.start_pos,
+            (ParseErrorType::NoMoreTokens, None) => usize::MAX,
+            (_, None) => 0,
+        }
+    }
+
+    /// Whichever of the errors is further, or the other one if they're as far
+    fn furthest(self, other: ParseError<'a>) -> ParseError<'a> {
+        if self.position() > other.position() {
+            self
+        } else {
+            other
+        }
+    }
+}
+
 type Result<'a, Success> = std::result::Result<Success, ParseError<'a>>;
 
+/// A parser giving the tokens left after what it parsed
+type Parser<'a, T> = dyn Fn(&'a [Token<'a>]) -> Result<'a, (&'a [Token<'a>], T)>;
+
+/// The function and trailing arguments of a single infix call
+type InfixCall<'a> = (LocatedExpression<'a>, Vec<LocatedExpression<'a>>);
+
 trait Take {
     type Output;
     type Check;
-    fn take_matching(&self, item: Self::Check) -> Result<(&Self, &Self::Output)>;
+    fn take_matching(&self, item: Self::Check) -> Result<'_, (&Self, &Self::Output)>;
 }
 
 impl<'a> Take for [Token<'a>] {
     type Output = Token<'a>;
     type Check = TokenData<'a>;
 
-    fn take_matching(&self, item: Self::Check) -> Result<(&Self, &Self::Output)> {
-        self.get(0)
+    fn take_matching(&self, item: Self::Check) -> Result<'_, (&Self, &Self::Output)> {
+        self.first()
             .map(|i| {
                 if std::mem::discriminant(&i.data) == std::mem::discriminant(&item) {
                     Ok((&self[1..], i))
@@ -63,32 +124,36 @@ impl<'a> Take for [Token<'a>] {
     }
 }
 
+/// Parse an expression followed by any number of `!` calls and `.member` accesses
 pub fn parse_no_arg_call<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
-    let (t, e) = parse_non_left_recursive_expression(tokens)?;
-    let (t, f) = t.take_matching(TokenData::ExclamationMark)?;
-    let mut t = t;
-    let mut calls = vec![f];
-    while let Ok((new_t, f)) = t.take_matching(TokenData::ExclamationMark) {
-        t = new_t;
-        calls.push(f);
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (mut t, mut e) = parse_non_left_recursive_expression(tokens)?;
+    loop {
+        if let Ok((new_t, f)) = t.take_matching(TokenData::ExclamationMark) {
+            let location = Location::between(&e.location, &f.location);
+            e = Expression::Call(Box::new(e), vec![]).with_location(location);
+            t = new_t;
+        } else if let Ok((new_t, member)) = t
+            .take_matching(TokenData::Dot)
+            .and_then(|(t, _)| parse_symbol(t))
+        {
+            let location = Location::between(&e.location, &member.location);
+            let Expression::Symbol(member) = member.expression else {
+                unreachable!()
+            };
+            e = Expression::Member(Box::new(e), member).with_location(location);
+            t = new_t;
+        } else {
+            break;
+        }
     }
-    Ok((
-        t,
-        calls.into_iter().fold(e.clone(), |a, v| {
-            Expression::Call(Box::new(a.clone()), vec![])
-                .with_location(Location::between(&e.location, &v.location))
-        }),
-    ))
+    Ok((t, e))
 }
 
 pub fn infix_call_inner<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(
-    &'a [Token<'a>],
-    (LocatedExpression<'a>, Vec<LocatedExpression<'a>>),
-)> {
+) -> Result<'a, (&'a [Token<'a>], InfixCall<'a>)> {
     let mut t;
     let mut first_call;
     t = tokens;
@@ -104,9 +169,10 @@ pub fn infix_call_inner<'a>(
     Ok((t, (first_call, args)))
 }
 
+/// Parse an expression followed by any number of infix calls
 pub fn parse_infix_call<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
     let (t, first) = parse_left_recursive_expression_1(tokens)?;
     let mut t = t;
     let mut rest = vec![];
@@ -114,29 +180,26 @@ pub fn parse_infix_call<'a>(
         t = new_t;
         rest.push(e);
     }
-    if rest.len() == 0 {
-        Err(ParseErrorType::IncompleteInfix.with_location(first.location))
-    } else {
-        Ok((
-            t,
-            rest.into_iter().fold(first, |a, (func, other_args)| {
-                let location =
-                    Location::between(&a.location, &other_args.last().unwrap_or(&func).location);
-                let mut args = vec![a];
-                args.extend(other_args.into_iter());
+    Ok((
+        t,
+        rest.into_iter().fold(first, |a, (func, other_args)| {
+            let location =
+                Location::between(&a.location, &other_args.last().unwrap_or(&func).location);
+            let mut args = vec![a];
+            args.extend(other_args);
 
-                LocatedExpression {
-                    expression: Expression::Call(Box::new(func), args),
-                    location,
-                }
-            }),
-        ))
-    }
+            LocatedExpression {
+                expression: Expression::Call(Box::new(func), args),
+                location,
+            }
+        }),
+    ))
 }
 
+/// Parse an expression followed by any number of arguments to call it with
 pub fn parse_normal_call<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
     let (t, func) = parse_left_recursive_expression_2(tokens)?;
     let mut args = vec![];
     let mut t = t;
@@ -145,64 +208,129 @@ pub fn parse_normal_call<'a>(
         t = new_t;
     }
 
-    args.last()
-        .map(|last| {
+    match args.last() {
+        Some(last) => {
             let location = Location::between(&func.location, &last.location);
-            (
+            Ok((
                 t,
                 LocatedExpression {
-                    expression: Expression::Call(Box::new(func.clone()), args.clone()),
+                    expression: Expression::Call(Box::new(func), args),
                     location,
                 },
-            )
-        })
-        .ok_or(ParseErrorType::NoArgumentsToCall.with_location(func.location))
+            ))
+        }
+        None => Ok((t, func)),
+    }
+}
+
+/// Parse with the first of the parsers which succeeds. If none do, the error is from
+/// whichever got furthest, as that's the one the tokens were most likely meant to be
+fn parse_first_of<'a, T>(
+    tokens: &'a [Token<'a>],
+    parsers: &[&Parser<'a, T>],
+) -> Result<'a, (&'a [Token<'a>], T)> {
+    let mut error: Option<ParseError<'a>> = None;
+    for parser in parsers {
+        match parser(tokens) {
+            Ok(parsed) => return Ok(parsed),
+            Err(e) => {
+                error = Some(match error {
+                    Some(error) => error.furthest(e),
+                    None => e,
+                })
+            }
+        }
+    }
+    Err(error.unwrap_or_else(|| ParseErrorType::NoMoreTokens.into()))
 }
 
 pub fn parse_non_left_recursive_expression<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
-    parse_literal(tokens)
-        .or_else(|_| parse_symbol(tokens))
-        .or_else(|_| parse_function(tokens))
-        .or_else(|_| parse_cond_block(tokens))
-        .or_else(|_| parse_scoped_block(tokens))
-        .or_else(|_| parse_unscoped_block(tokens))
-        .or_else(|_| parse_assignment(tokens))
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let parsers: &[&Parser<'a, LocatedExpression<'a>>] = &[
+        &parse_literal,
+        &parse_symbol,
+        &parse_function,
+        &parse_cond_block,
+        &parse_match_block,
+        &parse_scoped_block,
+        &parse_unscoped_block,
+        &parse_assignment,
+        &parse_extract,
+        &parse_box,
+        &parse_dereference,
+        &parse_with,
+        &parse_import,
+        &parse_module,
+        &parse_behaviour,
+        &parse_context,
+        &|t| parse_keyword_prefixed(t, TokenData::Yield, Expression::Yield),
+        &|t| parse_keyword_prefixed(t, TokenData::Spawn, Expression::Spawn),
+        &|t| parse_keyword_prefixed(t, TokenData::Launch, Expression::Launch),
+        &|t| parse_keyword_prefixed(t, TokenData::Raise, Expression::Raise),
+        &parse_try,
+    ];
+    parse_first_of(tokens, parsers)
 }
 
 pub fn parse_left_recursive_expression_1<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
-    parse_no_arg_call(tokens).or_else(|_| parse_non_left_recursive_expression(tokens))
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    // Each level of the grammar parses its operand once, rather than trying the
+    // call and falling back, which takes exponential time in the nesting depth
+    parse_no_arg_call(tokens)
 }
 
 pub fn parse_left_recursive_expression_2<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
-    parse_infix_call(tokens).or_else(|_| parse_left_recursive_expression_1(tokens))
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    parse_infix_call(tokens)
+}
+
+/// Parse the tokens as a single expression, failing if any are left over
+pub fn parse_all<'a>(tokens: &'a [Token<'a>]) -> Result<'a, LocatedExpression<'a>> {
+    let mut error = match parse_expression(tokens) {
+        Ok((rest, expression)) => {
+            let Some(token) = rest.first() else {
+                return Ok(expression);
+            };
+            let unexpected = ParseErrorType::UnexpectedToken.with_location(token.location.clone());
+            // The expression may have stopped before something it couldn't parse, like an
+            // argument, whose own error says more if it got further
+            match parse_expression(rest) {
+                Ok(_) => unexpected,
+                Err(e) => e.furthest(unexpected),
+            }
+        }
+        Err(e) => e,
+    };
+    // Running out of tokens is put just after the last one
+    if error.location.is_none() {
+        error.location = tokens.last().map(|last| Location {
+            start_pos: last.location.end_pos,
+            ..last.location.clone()
+        });
+    }
+    Err(error)
 }
 
 pub fn parse_expression<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
-    parse_normal_call(tokens).or_else(|_| parse_left_recursive_expression_2(tokens))
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    parse_normal_call(tokens)
 }
 
 fn parse_assignment_pair<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(&'a [Token<'a>], (Symbol, LocatedExpression<'a>))> {
-    let (t, symbol) = tokens.take_matching(TokenData::Symbol(""))?;
+) -> Result<'a, (&'a [Token<'a>], (LocatedPattern<'a>, LocatedExpression<'a>))> {
+    let (t, pattern) = parse_located_pattern(tokens)?;
     let (t, exp) = parse_expression(t)?;
-    match symbol.data {
-        TokenData::Symbol(sym) => Ok((t, (Symbol(sym.into()), exp))),
-        _ => Err(ParseErrorType::CouldNotMatchSymbol.with_location(symbol.location.clone())),
-    }
+    Ok((t, (pattern, exp)))
 }
 
 fn parse_assignment<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
     let mut t;
     let (new_t, start, recursive) = tokens
         .take_matching(TokenData::Let)
@@ -237,18 +365,159 @@ fn parse_assignment<'a>(
     ))
 }
 
-fn parse_function<'a>(tokens: &'a [Token<'a>]) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
-    let mut t = tokens;
-    let (new_t, start) = t.take_matching(TokenData::Pipe)?;
-    t = new_t;
-    let mut arguments = vec![];
+fn parse_extract<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (mut t, start) = tokens.take_matching(TokenData::Extract)?;
+    let mut symbols = vec![];
     while let Ok((new_t, i)) = t.take_matching(TokenData::Symbol("")) {
-        arguments.push(match i.data {
+        symbols.push(match i.data {
             TokenData::Symbol(s) => Ok(Symbol(s.into())),
             _ => Err(ParseErrorType::CouldNotMatchSymbol.with_location(i.location.clone())),
         }?);
         t = new_t
     }
+    (t, _) = t.take_matching(TokenData::Colon)?;
+    let (t, dict) = parse_expression(t)?;
+    let location = Location::between(&start.location, &dict.location);
+    Ok((
+        t,
+        Expression::Extract(symbols, Box::new(dict)).with_location(location),
+    ))
+}
+
+fn parse_box<'a>(tokens: &'a [Token<'a>]) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (t, start) = tokens.take_matching(TokenData::Ampersand)?;
+    let (t, value) = parse_non_left_recursive_expression(t)?;
+    let location = Location::between(&start.location, &value.location);
+    Ok((
+        t,
+        Expression::MakeBox(Box::new(value)).with_location(location),
+    ))
+}
+
+fn parse_dereference<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (t, start) = tokens.take_matching(TokenData::At)?;
+    let (t, value) = parse_non_left_recursive_expression(t)?;
+    let location = Location::between(&start.location, &value.location);
+    Ok((
+        t,
+        Expression::Dereference(Box::new(value)).with_location(location),
+    ))
+}
+
+fn parse_with<'a>(tokens: &'a [Token<'a>]) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (t, start) = tokens.take_matching(TokenData::With)?;
+    let (t, context) = parse_non_left_recursive_expression(t)?;
+    let (t, body) = parse_expression(t)?;
+    let location = Location::between(&start.location, &body.location);
+    Ok((
+        t,
+        Expression::With(Box::new(context), Box::new(body)).with_location(location),
+    ))
+}
+
+/// Parse a keyword followed by an expression
+fn parse_keyword_prefixed<'a>(
+    tokens: &'a [Token<'a>],
+    keyword: TokenData<'a>,
+    expression: fn(Box<LocatedExpression<'a>>) -> Expression<'a>,
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (t, start) = tokens.take_matching(keyword)?;
+    let (t, value) = parse_expression(t)?;
+    let location = Location::between(&start.location, &value.location);
+    Ok((t, expression(Box::new(value)).with_location(location)))
+}
+
+fn parse_try<'a>(tokens: &'a [Token<'a>]) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (t, start) = tokens.take_matching(TokenData::Try)?;
+    let (t, body) = parse_expression(t)?;
+    let (t, _) = t.take_matching(TokenData::Catch)?;
+    let (t, handler) = parse_expression(t)?;
+    let location = Location::between(&start.location, &handler.location);
+    Ok((
+        t,
+        Expression::Try(Box::new(body), Box::new(handler)).with_location(location),
+    ))
+}
+
+fn parse_import<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (t, start) = tokens.take_matching(TokenData::Import)?;
+    let (t, path) = parse_string(t)?;
+    let location = Location::between(&start.location, &path.location);
+    match path.expression {
+        Expression::Literal(Literal::String(path)) => {
+            Ok((t, Expression::Import(path).with_location(location)))
+        }
+        _ => Err(ParseErrorType::CouldNotMatchString.with_location(location)),
+    }
+}
+
+fn parse_module<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (t, start) = tokens.take_matching(TokenData::Module)?;
+    let (t, _) = t.take_matching(TokenData::OpenCurlyBracket)?;
+    let (t, assignment) = parse_assignment(t)?;
+    let (t, close) = t.take_matching(TokenData::CloseCurlyBracket)?;
+    let location = Location::between(&start.location, &close.location);
+    match assignment.expression {
+        Expression::Let(l) => Ok((t, Expression::Module(l).with_location(location))),
+        _ => unreachable!(),
+    }
+}
+
+fn parse_behaviour<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (t, start) = tokens.take_matching(TokenData::Behaviour)?;
+    let (mut t, _) = t.take_matching(TokenData::OpenCurlyBracket)?;
+    let mut methods = vec![];
+    while let Ok((new_t, method)) = parse_symbol(t) {
+        if let Expression::Symbol(method) = method.expression {
+            methods.push(method);
+        }
+        t = new_t;
+        if let Ok((new_t, _)) = t.take_matching(TokenData::Comma) {
+            t = new_t
+        } else {
+            break;
+        }
+    }
+    let (t, close) = t.take_matching(TokenData::CloseCurlyBracket)?;
+    let location = Location::between(&start.location, &close.location);
+    Ok((t, Expression::Behaviour(methods).with_location(location)))
+}
+
+fn parse_context<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (t, start) = tokens.take_matching(TokenData::Caret)?;
+    let (t, quoted) = parse_quoted_symbol(t)?;
+    let location = Location::between(&start.location, &quoted.location);
+    match quoted.expression {
+        Expression::Literal(Literal::Quoted(sym)) => {
+            Ok((t, Expression::Context(sym).with_location(location)))
+        }
+        _ => Err(ParseErrorType::CouldNotMatchSymbol.with_location(location)),
+    }
+}
+
+fn parse_function<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let mut t = tokens;
+    let (new_t, start) = t.take_matching(TokenData::Pipe)?;
+    t = new_t;
+    let mut arguments = vec![];
+    while let Ok((new_t, p)) = parse_located_pattern(t) {
+        arguments.push(p);
+        t = new_t
+    }
     (t, _) = t.take_matching(TokenData::Pipe)?;
 
     let (new_t, body) = parse_expression(t)?;
@@ -262,7 +531,9 @@ fn parse_function<'a>(tokens: &'a [Token<'a>]) -> Result<(&'a [Token<'a>], Locat
     ))
 }
 
-fn parse_symbol<'a>(tokens: &'a [Token<'a>]) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
+fn parse_symbol<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
     let (t, s) = tokens.take_matching(TokenData::Symbol(""))?;
     match s.data {
         TokenData::Symbol(sym) => Ok((
@@ -276,21 +547,34 @@ fn parse_symbol<'a>(tokens: &'a [Token<'a>]) -> Result<(&'a [Token<'a>], Located
     }
 }
 
-fn parse_number<'a>(tokens: &'a [Token<'a>]) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
-    let (t, s) = tokens.take_matching(TokenData::Number(""))?;
-    match s.data {
-        TokenData::Number(num) => Ok((
+fn parse_number<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (t, s) = tokens
+        .take_matching(TokenData::Int(""))
+        .or_else(|_| tokens.take_matching(TokenData::Float("")))?;
+    let literal = match s.data {
+        TokenData::Int(num) => num
+            .parse()
+            .map(Literal::Int)
+            .or_else(|_| num.parse().map(Literal::BigInt))
+            .ok(),
+        TokenData::Float(num) => num.parse().map(Literal::Float).ok(),
+        _ => None,
+    };
+    match literal {
+        Some(literal) => Ok((
             t,
             LocatedExpression {
-                expression: Expression::Literal(Literal::Number(num.parse().unwrap())),
+                expression: Expression::Literal(literal),
                 location: s.location.clone(),
             },
         )),
-        _ => Err(ParseErrorType::CouldNotMatchNumber.with_location(s.location.clone())),
+        None => Err(ParseErrorType::CouldNotMatchNumber.with_location(s.location.clone())),
     }
 }
 
-fn parse_list<'a>(tokens: &'a [Token<'a>]) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
+fn parse_list<'a>(tokens: &'a [Token<'a>]) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
     let mut t = tokens;
     let (new_t, open_b) = t.take_matching(TokenData::OpenSquareBracket)?;
 
@@ -318,17 +602,20 @@ fn parse_list<'a>(tokens: &'a [Token<'a>]) -> Result<(&'a [Token<'a>], LocatedEx
 
 fn dict_element<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(
-    &'a [Token<'a>],
-    (LocatedExpression<'a>, LocatedExpression<'a>),
-)> {
+) -> Result<
+    'a,
+    (
+        &'a [Token<'a>],
+        (LocatedExpression<'a>, LocatedExpression<'a>),
+    ),
+> {
     let (t, k) = parse_expression(tokens)?;
     let (t, _) = t.take_matching(TokenData::Colon)?;
     let (t, v) = parse_expression(t)?;
     Ok((t, (k, v)))
 }
 
-fn parse_dict<'a>(tokens: &'a [Token<'a>]) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
+fn parse_dict<'a>(tokens: &'a [Token<'a>]) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
     let mut t = tokens;
     let (new_t, open_b) = t.take_matching(TokenData::OpenAngleBracket)?;
     t = new_t;
@@ -350,48 +637,92 @@ fn parse_dict<'a>(tokens: &'a [Token<'a>]) -> Result<(&'a [Token<'a>], LocatedEx
     ))
 }
 
-fn parse_string<'a>(tokens: &'a [Token<'a>]) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
+fn parse_string<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
     tokens
-        .get(0)
-        .map(|t| match t.data {
+        .first()
+        .map(|t| match &t.data {
             TokenData::String(s) => Ok((
                 &tokens[1..],
-                Expression::Literal(Literal::String(s.into())).with_location(t.location.clone()),
+                Expression::Literal(Literal::String(s.to_string()))
+                    .with_location(t.location.clone()),
             )),
             _ => Err(ParseErrorType::CouldNotMatchString.with_location(t.location.clone())),
         })
         .unwrap_or(Err(ParseErrorType::NoMoreTokens.into()))
 }
 
-fn parse_literal<'a>(tokens: &'a [Token<'a>]) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
-    let f = || {
-        for (token_data, literal) in [
-            (TokenData::Nil, Literal::Nil),
-            (TokenData::False, Literal::Bool(false)),
-            (TokenData::True, Literal::Bool(true)),
-        ] {
-            if let Ok((t, tok)) = tokens.take_matching(token_data) {
-                return Ok((
-                    t,
-                    LocatedExpression {
-                        expression: Expression::Literal(literal),
-                        location: tok.location.clone(),
-                    },
-                ));
+/// Parse a string with embedded expressions into its literal text and expressions, in order
+fn parse_interpolation<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (mut t, start) = tokens.take_matching(TokenData::InterpolationStart("".into()))?;
+    let mut parts = vec![];
+    let mut text = start;
+    loop {
+        if let TokenData::InterpolationStart(s)
+        | TokenData::InterpolationMiddle(s)
+        | TokenData::InterpolationEnd(s) = &text.data
+        {
+            if !s.is_empty() {
+                parts.push(
+                    Expression::Literal(Literal::String(s.to_string()))
+                        .with_location(text.location.clone()),
+                );
             }
         }
-        Err(ParseErrorType::CouldNotMatchSymbol.into())
-    };
-    f().or_else(|_: ParseError| parse_list(tokens))
-        .or_else(|_| parse_dict(tokens))
-        .or_else(|_| parse_number(tokens))
-        .or_else(|_| parse_string(tokens))
-        .or_else(|_| parse_quoted_symbol(tokens))
+        if let TokenData::InterpolationEnd(_) = text.data {
+            let location = Location::between(&start.location, &text.location);
+            return Ok((t, Expression::Interpolation(parts).with_location(location)));
+        }
+        let (new_t, part) = parse_expression(t)?;
+        parts.push(part);
+        (t, text) = new_t
+            .take_matching(TokenData::InterpolationMiddle("".into()))
+            .or_else(|_| new_t.take_matching(TokenData::InterpolationEnd("".into())))?;
+    }
+}
+
+fn parse_keyword_literal<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    for (token_data, literal) in [
+        (TokenData::Nil, Literal::Nil),
+        (TokenData::False, Literal::Bool(false)),
+        (TokenData::True, Literal::Bool(true)),
+    ] {
+        if let Ok((t, tok)) = tokens.take_matching(token_data) {
+            return Ok((
+                t,
+                LocatedExpression {
+                    expression: Expression::Literal(literal),
+                    location: tok.location.clone(),
+                },
+            ));
+        }
+    }
+    Err(ParseErrorType::CouldNotMatchSymbol.into())
+}
+
+fn parse_literal<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let parsers: &[&Parser<'a, LocatedExpression<'a>>] = &[
+        &parse_keyword_literal,
+        &parse_list,
+        &parse_dict,
+        &parse_number,
+        &parse_string,
+        &parse_interpolation,
+        &parse_quoted_symbol,
+    ];
+    parse_first_of(tokens, parsers)
 }
 
 fn parse_quoted_symbol<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
     let (t, d) = tokens.take_matching(TokenData::Dollar)?;
     let (t, s) = parse_symbol(t)?;
     let location = Location::between(&d.location, &s.location);
@@ -428,7 +759,7 @@ fn parse_block<'a>(
         Block {
             scope_introducing,
 
-            ignored: rest.iter().map(|x| x.clone()).collect(),
+            ignored: rest.to_vec(),
             last: Box::new(last.clone()),
         }
     } else {
@@ -444,7 +775,7 @@ fn parse_block<'a>(
 
 fn parse_scoped_block<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
     parse_block(
         tokens,
         (TokenData::OpenCurlyBracket, TokenData::CloseCurlyBracket),
@@ -454,16 +785,19 @@ fn parse_scoped_block<'a>(
 
 fn parse_unscoped_block<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
     parse_block(tokens, (TokenData::OpenParen, TokenData::CloseParen), false)
 }
 
 fn parse_condition<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(
-    &'a [Token<'a>],
-    (LocatedExpression<'a>, LocatedExpression<'a>),
-)> {
+) -> Result<
+    'a,
+    (
+        &'a [Token<'a>],
+        (LocatedExpression<'a>, LocatedExpression<'a>),
+    ),
+> {
     let (t, c) = parse_expression(tokens)?;
     let (t, _) = t.take_matching(TokenData::Tilde)?;
     let (t, r) = parse_expression(t)?;
@@ -473,7 +807,7 @@ fn parse_condition<'a>(
 
 fn parse_cond_block<'a>(
     tokens: &'a [Token<'a>],
-) -> Result<(&'a [Token<'a>], LocatedExpression<'a>)> {
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
     let mut t = tokens;
     let (new_t, start) = t.take_matching(TokenData::Cond)?;
     t = new_t;
@@ -495,17 +829,138 @@ fn parse_cond_block<'a>(
     ))
 }
 
+/// Parse a literal which can be turned into a value at compile time
+fn parse_constant_literal<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], Literal<'a>)> {
+    let (t, e) = parse_literal(tokens)?;
+    match e.expression {
+        Expression::Literal(l) if l.is_constant() => Ok((t, l)),
+        _ => Err(ParseErrorType::NonConstantPattern.with_location(e.location)),
+    }
+}
+
+fn parse_symbol_pattern<'a>(tokens: &'a [Token<'a>]) -> Result<'a, (&'a [Token<'a>], Pattern<'a>)> {
+    let (t, s) = parse_symbol(tokens)?;
+    match s.expression {
+        Expression::Symbol(Symbol(sym)) if sym == "_" => Ok((t, Pattern::Wildcard)),
+        Expression::Symbol(sym) => Ok((t, Pattern::Bind(sym))),
+        _ => Err(ParseErrorType::CouldNotMatchSymbol.with_location(s.location)),
+    }
+}
+
+fn parse_list_pattern<'a>(tokens: &'a [Token<'a>]) -> Result<'a, (&'a [Token<'a>], Pattern<'a>)> {
+    let (mut t, _) = tokens.take_matching(TokenData::OpenSquareBracket)?;
+    let mut elements = vec![];
+    let mut rest = None;
+    loop {
+        if let Ok((new_t, _)) = t.take_matching(TokenData::DotDot) {
+            // A bare `..` ignores the rest of the list
+            let (new_t, r) = parse_pattern(new_t).unwrap_or((new_t, Pattern::Wildcard));
+            rest = Some(Box::new(r));
+            t = new_t;
+            break;
+        }
+        match parse_pattern(t) {
+            Ok((new_t, p)) => {
+                elements.push(p);
+                t = new_t;
+            }
+            Err(_) => break,
+        }
+        if let Ok((new_t, _)) = t.take_matching(TokenData::Comma) {
+            t = new_t
+        } else {
+            break;
+        }
+    }
+    let (t, _) = t.take_matching(TokenData::CloseSquareBracket)?;
+    Ok((t, Pattern::List(elements, rest)))
+}
+
+fn parse_dict_pattern<'a>(tokens: &'a [Token<'a>]) -> Result<'a, (&'a [Token<'a>], Pattern<'a>)> {
+    let (mut t, _) = tokens.take_matching(TokenData::OpenAngleBracket)?;
+    let mut entries = vec![];
+    while let Ok((new_t, p)) = parse_pattern(t) {
+        let (new_t, _) = new_t.take_matching(TokenData::Colon)?;
+        let (new_t, key) = parse_constant_literal(new_t)?;
+        entries.push((p, key));
+        t = new_t;
+        if let Ok((new_t, _)) = t.take_matching(TokenData::Comma) {
+            t = new_t
+        } else {
+            break;
+        }
+    }
+    let (t, _) = t.take_matching(TokenData::CloseAngleBracket)?;
+    Ok((t, Pattern::Dictionary(entries)))
+}
+
+fn parse_pattern<'a>(tokens: &'a [Token<'a>]) -> Result<'a, (&'a [Token<'a>], Pattern<'a>)> {
+    let parsers: &[&Parser<'a, Pattern<'a>>] = &[
+        &parse_list_pattern,
+        &parse_dict_pattern,
+        &parse_symbol_pattern,
+        &|t| parse_constant_literal(t).map(|(t, l)| (t, Pattern::Literal(l))),
+    ];
+    parse_first_of(tokens, parsers)
+}
+
+/// Parse a pattern, keeping the location of the tokens it was parsed from
+fn parse_located_pattern<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedPattern<'a>)> {
+    let (t, pattern) = parse_pattern(tokens)?;
+    let consumed = tokens.len() - t.len();
+    let location = Location::between(&tokens[0].location, &tokens[consumed - 1].location);
+    Ok((t, LocatedPattern { pattern, location }))
+}
+
+fn parse_match_arm<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], (Pattern<'a>, LocatedExpression<'a>))> {
+    let (t, p) = parse_pattern(tokens)?;
+    let (t, _) = t.take_matching(TokenData::Tilde)?;
+    let (t, r) = parse_expression(t)?;
+    let (t, _) = t.take_matching(TokenData::SemiColon)?;
+    Ok((t, (p, r)))
+}
+
+fn parse_match_block<'a>(
+    tokens: &'a [Token<'a>],
+) -> Result<'a, (&'a [Token<'a>], LocatedExpression<'a>)> {
+    let (t, start) = tokens.take_matching(TokenData::Match)?;
+    let (t, value) = parse_left_recursive_expression_1(t)?;
+    let (mut t, _) = t.take_matching(TokenData::OpenCurlyBracket)?;
+    let mut arms = vec![];
+    while let Ok((new_t, arm)) = parse_match_arm(t) {
+        t = new_t;
+        arms.push(arm);
+    }
+    let (t, _) = t.take_matching(TokenData::Else)?;
+    let (t, else_exp) = parse_expression(t)?;
+    let (t, close) = t.take_matching(TokenData::CloseCurlyBracket)?;
+    Ok((
+        t,
+        Expression::Match(Box::new(value), arms, Box::new(else_exp))
+            .with_location(Location::between(&start.location, &close.location)),
+    ))
+}
+
 #[cfg(test)]
 mod test {
-    use crate::expression::{Expression, Literal, LocatedExpression, Symbol};
-    use crate::parser::{parse_list, Take};
+    use std::rc::Rc;
+
+    use crate::expression::{Expression, Literal, LocatedExpression, Pattern, Symbol};
+    use crate::parser::{parse_all, parse_expression, parse_list, parse_pattern, Take};
     use crate::tokeniser::{Location, Token, TokenData};
 
     use super::parse_symbol;
 
     #[test]
     fn take_works() {
-        let tokens = Token::tokenise_source("a|a| a", "")
+        let source: Rc<str> = "a|a| a".into();
+        let tokens = Token::tokenise_source(&source, "")
             .map(|i| i.unwrap())
             .collect::<Vec<_>>();
         assert_eq!(
@@ -519,9 +974,10 @@ mod test {
 
     #[test]
     fn parse_symbol_works() {
+        let source = "sym`".into();
         assert_eq!(
             parse_symbol(
-                &Token::tokenise_source("sym`", "")
+                &Token::tokenise_source(&source, "")
                     .map(|i| i.unwrap())
                     .collect::<Vec<_>>(),
             )
@@ -530,7 +986,7 @@ mod test {
                 expression: Expression::Symbol(Symbol("sym".into())),
                 location: Location {
                     file: "",
-                    source: "sym`",
+                    source: &source,
                     start_pos: 0,
                     end_pos: 3
                 }
@@ -538,14 +994,127 @@ mod test {
         );
     }
 
+    /// The calls in the expression as s-expressions, so the grammar can be checked
+    fn shape(e: &LocatedExpression) -> String {
+        match &e.expression {
+            Expression::Symbol(Symbol(s)) => s.clone(),
+            Expression::Call(f, args) => format!(
+                "({}{})",
+                shape(f),
+                args.iter()
+                    .map(|a| format!(" {}", shape(a)))
+                    .collect::<String>()
+            ),
+            Expression::Block(block) if !block.scope_introducing => shape(&block.last),
+            other => format!("{:?}", other),
+        }
+    }
+
+    fn parse_shape(source: &str) -> String {
+        let source: Rc<str> = source.into();
+        let tokens = Token::tokenise_source(&source, "")
+            .map(|i| i.unwrap())
+            .collect::<Vec<_>>();
+        let (rest, e) = parse_expression(&tokens).unwrap();
+        assert!(rest.is_empty(), "{} left {:?}", source, rest);
+        shape(&e)
+    }
+
+    #[test]
+    fn calls_parse_with_the_right_precedence() {
+        // Operands on their own are just themselves, whichever level they're parsed at
+        assert_eq!(parse_shape("x"), "x");
+        assert_eq!(parse_shape("f!"), "(f)");
+        assert_eq!(parse_shape("f!!"), "((f))");
+        assert_eq!(parse_shape("f a b"), "(f a b)");
+        assert_eq!(parse_shape("a `+ b `* c"), "(* (+ a b) c)");
+        assert_eq!(parse_shape("f a `+ b"), "(f (+ a b))");
+        assert_eq!(parse_shape("f! `+ g!"), "(+ (f) (g))");
+        assert_eq!(parse_shape("f (g x) y"), "(f (g x) y)");
+        // Each level parses its operand once, so deep nesting doesn't take exponential time
+        let depth = 100;
+        let nested = format!("{}x{}", "(f ".repeat(depth), ")".repeat(depth));
+        assert_eq!(parse_shape(&nested), nested);
+    }
+
+    #[test]
+    fn interpolated_expressions_keep_their_locations() {
+        let source: Rc<str> = r#"f "a {x `+ "{y}"} b""#.into();
+        let tokens = Token::tokenise_source(&source, "")
+            .map(|i| i.unwrap())
+            .collect::<Vec<_>>();
+        let (rest, call) = parse_expression(&tokens).unwrap();
+        assert!(rest.is_empty());
+        let Expression::Call(_, args) = call.expression else {
+            panic!("expected a call, got {:?}", call)
+        };
+        let Expression::Interpolation(parts) = &args[0].expression else {
+            panic!("expected an interpolation, got {:?}", args[0])
+        };
+        let text = |e: &LocatedExpression| &source[e.location.start_pos..e.location.end_pos];
+        assert_eq!(text(&args[0]), r#""a {x `+ "{y}"} b""#);
+        assert_eq!(
+            parts.iter().map(text).collect::<Vec<_>>(),
+            vec![r#""a {"#, r#"x `+ "{y}""#, r#"} b""#]
+        );
+        let Expression::Call(_, infix_args) = &parts[1].expression else {
+            panic!("expected a call, got {:?}", parts[1])
+        };
+        let Expression::Interpolation(inner) = &infix_args[1].expression else {
+            panic!("expected an interpolation, got {:?}", infix_args[1])
+        };
+        assert_eq!(inner.len(), 1);
+        assert_eq!(text(&inner[0]), "y");
+    }
+
+    #[test]
+    fn errors_are_from_the_alternative_which_got_furthest() {
+        let error = |source: &str| {
+            let source: Rc<str> = source.into();
+            let tokens = Token::tokenise_source(&source, "")
+                .map(|i| i.unwrap())
+                .collect::<Vec<_>>();
+            let error = parse_all(&tokens).unwrap_err();
+            (error.to_string(), error.span().unwrap().start_pos)
+        };
+        assert_eq!(error("(1"), ("unexpected end of input".into(), 2));
+        assert_eq!(error("{let x 1; x"), ("unexpected end of input".into(), 11));
+        // Including when the expression stopped before an argument it couldn't parse
+        assert_eq!(error("f (g x"), ("unexpected end of input".into(), 6));
+        assert_eq!(error("let [a, ) 1"), ("unexpected `)`".into(), 8));
+        assert_eq!(error("f x)"), ("unexpected `)`".into(), 3));
+    }
+
     #[test]
     fn parse_list_works() {
         let source = "[a, b, c, 3, let x 2, y 3]";
         assert!(parse_list(
-            &Token::tokenise_source(source, "")
+            &Token::tokenise_source(&source.into(), "")
                 .map(|i| i.unwrap())
                 .collect::<Vec<_>>()
         )
         .is_ok())
     }
+
+    #[test]
+    fn parse_pattern_works() {
+        let source: Rc<str> = "[_, <x: $a>, 3, ..rest]".into();
+        let tokens = Token::tokenise_source(&source, "")
+            .map(|i| i.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            parse_pattern(&tokens).map(|x| x.1),
+            Ok(Pattern::List(
+                vec![
+                    Pattern::Wildcard,
+                    Pattern::Dictionary(vec![(
+                        Pattern::Bind(Symbol("x".into())),
+                        Literal::Quoted(Symbol("a".into()))
+                    )]),
+                    Pattern::Literal(Literal::Int(3)),
+                ],
+                Some(Box::new(Pattern::Bind(Symbol("rest".into()))))
+            ))
+        );
+    }
 }
@@ -0,0 +1,298 @@
+use std::{
+    cell::RefCell,
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+    rc::Rc,
+};
+
+use crate::{
+    behaviour::Builtins,
+    compiler::Compiler,
+    diagnostic::render,
+    module::{parse, tokenise, Modules},
+    opcode::OpCode,
+    tokeniser::{Token, TokenData, TokeniserErrorType},
+    value::{Dictionary, Function, Placeholder, Value},
+    vm::VM,
+};
+
+const HELP: &str = "Enter an expression to run it. Names bound by a let at the prompt stay bound.
+:ast EXPRESSION  show how the expression parses
+:dis EXPRESSION  show the opcodes the expression compiles to, without running it
+:reset           forget everything that has been bound
+:help            show this message
+:quit            leave, as does the end of the input";
+
+/// An interactive session. Every entry is compiled into the same top level frame,
+/// and run with the registers the last one left, so bindings carry across entries
+pub struct Repl {
+    compiler: Compiler,
+    /// The top level registers, as the last entry left them
+    registers: Vec<Placeholder>,
+    context: Dictionary,
+    modules: Rc<RefCell<Modules>>,
+    /// Kept between entries, so implementations of builtin behaviours are too
+    builtins: Rc<Builtins>,
+    /// How many entries have been compiled, which names the file each is compiled as,
+    /// so errors in functions from earlier entries still show the right source
+    entries: usize,
+}
+
+impl Repl {
+    pub fn new(context: Dictionary) -> Self {
+        Repl {
+            compiler: Compiler::new(),
+            registers: vec![],
+            context,
+            modules: Rc::default(),
+            builtins: Rc::default(),
+            entries: 0,
+        }
+    }
+
+    fn next_file(&mut self) -> String {
+        self.entries += 1;
+        format!("<repl {}>", self.entries)
+    }
+
+    /// Compile and run the entry. If anything goes wrong, the names it bound are forgotten
+    pub fn eval(&mut self, source: &str) -> Result<Value, String> {
+        let saved = self.compiler.clone();
+        let result = self.run_entry(source);
+        if result.is_err() {
+            self.compiler = saved;
+        }
+        result
+    }
+
+    fn run_entry(&mut self, source: &str) -> Result<Value, String> {
+        let file = self.next_file();
+        let source = source.into();
+        let tokens = tokenise(&source, &file).map_err(|e| e.render())?;
+        let expression = parse(&tokens).map_err(|e| e.render())?;
+        // Not in tail position, so the top level frame is never replaced or returned from
+        let result = self
+            .compiler
+            .compile_expression(None, &expression, false)
+            .map_err(|e| e.render())?;
+        let mut vm = VM::with_context(
+            self.compiler.take_top_level_function(),
+            self.context.clone(),
+        );
+        vm.modules = self.modules.clone();
+        vm.builtins = self.builtins.clone();
+        // Later entries only ever add registers, so the old ones all fit
+        vm.frames[0]
+            .registers
+            .splice(0..self.registers.len(), self.registers.iter().cloned());
+        let frame = vm.run_to_end().map_err(|e| e.render())?;
+        let value = result.map_or(Value::Nil, |r| frame.get_value_index(r).unwrap());
+        self.registers = frame.registers;
+        Ok(value)
+    }
+
+    /// Compile the entry without running it, listing the opcodes of it
+    /// and of the functions it creates
+    fn disassemble(&mut self, source: &str) -> Result<String, String> {
+        let mut compiler = self.compiler.clone();
+        let file = self.next_file();
+        let source = source.into();
+        let tokens = tokenise(&source, &file).map_err(|e| e.render())?;
+        let expression = parse(&tokens).map_err(|e| e.render())?;
+        compiler
+            .compile_expression(None, &expression, false)
+            .map_err(|e| e.render())?;
+        let mut listing = String::new();
+        write_opcodes(&compiler.take_top_level_function(), 0, &mut listing);
+        Ok(listing.trim_end().into())
+    }
+
+    /// What to show for an entry, or None if the session should end
+    pub fn respond(&mut self, entry: &str) -> Option<Result<String, String>> {
+        let (command, rest) = match entry.strip_prefix(':') {
+            Some(command) => command
+                .split_once(char::is_whitespace)
+                .unwrap_or((command, "")),
+            None if entry.is_empty() => return Some(Ok(String::new())),
+            None => {
+                return Some(self.eval(entry).map(|value| match value {
+                    Value::Nil => String::new(),
+                    value => value.to_string(),
+                }))
+            }
+        };
+        Some(match command {
+            "ast" => {
+                let file = self.next_file();
+                tokenise(&rest.into(), &file)
+                    .and_then(|tokens| parse(&tokens).map(|e| format!("{:?}", e)))
+                    .map_err(|e| e.render())
+            }
+            "dis" => self.disassemble(rest),
+            "reset" => {
+                *self = Repl::new(self.context.clone());
+                Ok(String::new())
+            }
+            "help" => Ok(HELP.into()),
+            "quit" => return None,
+            _ => Err(render(
+                &format!("unknown command :{}, try :help", command),
+                None,
+            )),
+        })
+    }
+}
+
+/// Write out the opcodes, then those of each function they create, indented
+fn write_opcodes(function: &Function, indent: usize, listing: &mut String) {
+    for (i, opcode) in function.opcodes.iter().enumerate() {
+        writeln!(listing, "{:indent$}{:>4} {:?}", "", i, opcode).unwrap();
+    }
+    for opcode in &function.opcodes {
+        if let OpCode::CreateClosure(index, _) = opcode {
+            writeln!(listing, "{:indent$}function {}:", "", index.0).unwrap();
+            write_opcodes(
+                &function.functions[usize::from(index.clone())],
+                indent + 2,
+                listing,
+            );
+        }
+    }
+}
+
+/// Whether the entry has brackets, strings or comments which haven't been closed yet,
+/// so the next line should be added to it
+pub fn needs_more_input(source: &str) -> bool {
+    let mut depth = 0;
+    for token in Token::tokenise_source(&source.into(), "") {
+        match token.map(|t| t.data) {
+            Err(e) => {
+                return matches!(
+                    e.error_type,
+                    TokeniserErrorType::OpenString | TokeniserErrorType::OpenComment
+                )
+            }
+            Ok(
+                TokenData::OpenParen
+                | TokenData::OpenSquareBracket
+                | TokenData::OpenCurlyBracket
+                | TokenData::OpenAngleBracket
+                | TokenData::InterpolationStart(_),
+            ) => depth += 1,
+            Ok(
+                TokenData::CloseParen
+                | TokenData::CloseSquareBracket
+                | TokenData::CloseCurlyBracket
+                | TokenData::CloseAngleBracket
+                | TokenData::InterpolationEnd(_),
+            ) => depth -= 1,
+            Ok(_) => (),
+        }
+    }
+    depth > 0
+}
+
+/// Read entries from stdin until it ends or `:quit` is entered
+pub fn run(context: Dictionary) -> io::Result<()> {
+    let mut repl = Repl::new(context);
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        let mut entry = String::new();
+        let mut prompt = "> ";
+        loop {
+            print!("{}", prompt);
+            io::stdout().flush()?;
+            let Some(line) = lines.next() else {
+                println!();
+                return Ok(());
+            };
+            entry.push_str(&line?);
+            entry.push('\n');
+            if !needs_more_input(&entry) {
+                break;
+            }
+            prompt = "... ";
+        }
+        match repl.respond(entry.trim()) {
+            None => return Ok(()),
+            Some(Ok(output)) if output.is_empty() => (),
+            Some(Ok(output)) => println!("{}", output),
+            Some(Err(message)) => eprintln!("{}", message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::value::{Dictionary, Value};
+
+    use super::{needs_more_input, Repl};
+
+    #[test]
+    fn bindings_last_between_entries() {
+        let mut repl = Repl::new(Dictionary::default());
+        repl.eval("let x 2, f |y| y `+ x").unwrap();
+        repl.eval("let b &0").unwrap();
+        assert_eq!(repl.eval("f x"), Ok(Value::Int(4)));
+        // Entries which fail don't bind anything
+        assert!(repl.eval("let z 1, w (1 `+ nil)").is_err());
+        assert!(repl.eval("z").is_err());
+        repl.eval("let x 10").unwrap();
+        assert_eq!(repl.eval("[f 1, x, @b]").unwrap().to_string(), "[3, 10, 0]");
+        repl.eval("implement Eq $number <$=: |a b| true>").unwrap();
+        assert_eq!(repl.eval("1 `= 2"), Ok(Value::Bool(true)));
+        repl.respond(":reset");
+        assert!(repl.eval("x").is_err());
+        assert_eq!(repl.eval("1 `= 2"), Ok(Value::Bool(false)));
+    }
+
+    #[test]
+    fn entries_have_their_own_constants() {
+        let mut repl = Repl::new(Dictionary::default());
+        repl.eval("let x \"x\"").unwrap();
+        for i in 1..=300 {
+            assert_eq!(
+                repl.respond(&format!("\"s{}\"", i)),
+                Some(Ok(format!("\"s{}\"", i)))
+            );
+        }
+        assert_eq!(repl.respond("x"), Some(Ok("\"x\"".into())));
+    }
+
+    #[test]
+    fn errors_show_the_entry_they_happened_in() {
+        let mut repl = Repl::new(Dictionary::default());
+        repl.eval("let f |x| x `+ nil").unwrap();
+        let error = repl.eval("f 1").unwrap_err();
+        assert!(error.contains("--> <repl 1>:1:11\n"));
+        assert!(error.contains("1 | let f |x| x `+ nil\n"));
+    }
+
+    #[test]
+    fn meta_commands_work() {
+        let mut repl = Repl::new(Dictionary::default());
+        let listing = repl.respond(":dis |x| x `+ 1").unwrap().unwrap();
+        assert!(listing.contains("CreateClosure"));
+        assert!(listing.contains("function 0:\n"));
+        assert!(listing.contains("InsertNativeFunction(Sum"));
+        assert!(repl
+            .respond(":ast f x")
+            .unwrap()
+            .unwrap()
+            .starts_with("[Call("));
+        assert!(repl.respond(":what").unwrap().is_err());
+        assert!(repl.respond(":quit").is_none());
+    }
+
+    #[test]
+    fn unclosed_entries_need_more_input() {
+        assert!(needs_more_input("{let x 2;\n"));
+        assert!(needs_more_input("[1, (2\n"));
+        assert!(needs_more_input("\"open\n"));
+        assert!(needs_more_input("\"a {b\n"));
+        assert!(needs_more_input("#| still a comment\n"));
+        assert!(!needs_more_input("{let x 2; x}\n"));
+        assert!(!needs_more_input("f x)\n"));
+        assert!(!needs_more_input("\"a {b} c\"\n"));
+    }
+}
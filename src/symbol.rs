@@ -0,0 +1,85 @@
+use std::{cell::RefCell, collections::HashMap, fmt::Debug, rc::Rc};
+
+use crate::{
+    expression::Symbol,
+    value::{Value, ValueError},
+};
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::default());
+}
+
+/// Maps symbol names to small integer ids, and back again
+#[derive(Default)]
+struct Interner {
+    names: Vec<Rc<str>>,
+    ids: HashMap<Rc<str>, u32>,
+}
+
+impl Interner {
+    fn intern(&mut self, name: &str) -> u32 {
+        if let Some(id) = self.ids.get(name) {
+            *id
+        } else {
+            let name: Rc<str> = name.into();
+            let id = self.names.len() as u32;
+            self.names.push(name.clone());
+            self.ids.insert(name, id);
+            id
+        }
+    }
+}
+
+/// A runtime symbol. Symbols are interned, so comparing two of them
+/// is a single integer comparison
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InternedSymbol(u32);
+
+impl InternedSymbol {
+    pub fn new(name: &str) -> InternedSymbol {
+        InternedSymbol(INTERNER.with(|i| i.borrow_mut().intern(name)))
+    }
+
+    /// The name this symbol was interned with
+    pub fn name(&self) -> Rc<str> {
+        INTERNER.with(|i| i.borrow().names[self.0 as usize].clone())
+    }
+
+    /// Calling a symbol with a dictionary looks up the symbol in the dictionary,
+    /// giving nil if it isn't there
+    pub fn call(&self, args: Vec<Value>) -> Result<Value, ValueError> {
+        match &args[..] {
+            [] => Ok(Value::Symbol(*self)),
+            [dict] => Ok(dict
+                .dictionary()?
+                .get(&Value::Symbol(*self))
+                .cloned()
+                .unwrap_or(Value::Nil)),
+            _ => Err(ValueError::TooManyArguments),
+        }
+    }
+}
+
+impl From<&Symbol> for InternedSymbol {
+    fn from(value: &Symbol) -> Self {
+        InternedSymbol::new(&value.0)
+    }
+}
+
+impl Debug for InternedSymbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("${}", self.name()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InternedSymbol;
+
+    #[test]
+    fn interning_works() {
+        assert_eq!(InternedSymbol::new("a"), InternedSymbol::new("a"));
+        assert_ne!(InternedSymbol::new("a"), InternedSymbol::new("b"));
+        assert_eq!(&*InternedSymbol::new("abc").name(), "abc");
+    }
+}
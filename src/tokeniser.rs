@@ -1,26 +1,80 @@
-use std::{error::Error, fmt::Debug};
+use std::{
+    borrow::Cow,
+    fmt::{Debug, Display},
+    rc::Rc,
+};
 
-use crate::expression::Symbol;
-
-#[derive(PartialEq, Debug)]
-pub enum TokeniserError {
+#[derive(PartialEq, Debug, Clone)]
+pub enum TokeniserErrorType {
     OpenString,
+    /// A block comment was never closed
+    OpenComment,
+    /// An escape in a string which isn't valid, at these positions in the source
+    InvalidEscape {
+        start_pos: usize,
+        end_pos: usize,
+    },
     NoMatch,
 }
 
-type Result<Success> = std::result::Result<Success, TokeniserError>;
+impl TokeniserErrorType {
+    /// Move any positions in the error along, from being relative to a later part of the source
+    fn offset_by(self, offset: usize) -> Self {
+        match self {
+            TokeniserErrorType::InvalidEscape { start_pos, end_pos } => {
+                TokeniserErrorType::InvalidEscape {
+                    start_pos: start_pos + offset,
+                    end_pos: end_pos + offset,
+                }
+            }
+            e => e,
+        }
+    }
+}
+
+impl Display for TokeniserErrorType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokeniserErrorType::OpenString => f.write_str("string is never closed"),
+            TokeniserErrorType::OpenComment => f.write_str("block comment is never closed"),
+            TokeniserErrorType::InvalidEscape { .. } => f.write_str("invalid escape in string"),
+            TokeniserErrorType::NoMatch => f.write_str("unexpected character"),
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct TokeniserError<'a> {
+    pub error_type: TokeniserErrorType,
+    pub location: Location<'a>,
+}
+
+impl<'a> Display for TokeniserError<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.error_type, f)
+    }
+}
+
+type Result<Success> = std::result::Result<Success, TokeniserErrorType>;
 
+/// The positions are byte offsets into the source, so always fall on character boundaries
 #[derive(PartialEq, Clone)]
 pub struct Location<'a> {
     pub file: &'a str,
-    pub source: &'a str,
+    /// Shared, so spans made from the location can keep the source alive
+    pub source: &'a Rc<str>,
     pub start_pos: usize,
     pub end_pos: usize,
 }
 
 impl<'a> Debug for Location<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("Loc ({} -> {})", self.start_pos, self.end_pos))
+        let (start_line, start_column) = self.line_and_column(self.start_pos);
+        let (end_line, end_column) = self.line_and_column(self.end_pos);
+        f.write_fmt(format_args!(
+            "Loc ({}:{} -> {}:{})",
+            start_line, start_column, end_line, end_column
+        ))
     }
 }
 
@@ -33,6 +87,52 @@ impl<'a> Location<'a> {
             end_pos: to.end_pos,
         }
     }
+
+    /// The line and column of the position in the source, both counting from 1.
+    /// Columns count characters, not bytes
+    pub fn line_and_column(&self, pos: usize) -> (usize, usize) {
+        Offset::of(&self.source[..pos]).line_and_column()
+    }
+}
+
+/// A region of a source file which keeps the source, for reporting runtime errors
+#[derive(Clone, PartialEq)]
+pub struct Span {
+    pub file: Rc<str>,
+    pub source: Rc<str>,
+    pub start_pos: usize,
+    pub end_pos: usize,
+}
+
+impl<'a> From<&Location<'a>> for Span {
+    fn from(value: &Location<'a>) -> Self {
+        Span {
+            file: value.file.into(),
+            source: value.source.clone(),
+            start_pos: value.start_pos,
+            end_pos: value.end_pos,
+        }
+    }
+}
+
+impl Debug for Span {
+    /// The source is left out, as it's the whole file
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Span")
+            .field("file", &self.file)
+            .field("start_pos", &self.start_pos)
+            .field("end_pos", &self.end_pos)
+            .finish()
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{}:{}..{}",
+            self.file, self.start_pos, self.end_pos
+        ))
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -61,9 +161,36 @@ pub enum TokenData<'a> {
     Nil,
     Else,
     Tilde,
-    Number(&'a str),
-    String(&'a str),
+    DotDot,
+    Dot,
+    Ampersand,
+    At,
+    Caret,
+    With,
+    Match,
+    Import,
+    Module,
+    Behaviour,
+    Yield,
+    Spawn,
+    Launch,
+    Raise,
+    Try,
+    Catch,
+    Int(&'a str),
+    Float(&'a str),
+    /// The value of a string, with its escapes decoded
+    String(Cow<'a, str>),
+    /// The start of a string with embedded expressions, up to the first `{`.
+    /// The tokens of the expression follow
+    InterpolationStart(Cow<'a, str>),
+    /// The part of an interpolated string between a `}` and the next `{`
+    InterpolationMiddle(Cow<'a, str>),
+    /// The rest of an interpolated string after its last `}`
+    InterpolationEnd(Cow<'a, str>),
     Symbol(&'a str),
+    /// A comment, only produced when tokenising with trivia
+    Comment(&'a str),
 }
 
 #[derive(Debug, PartialEq)]
@@ -73,76 +200,160 @@ pub struct Token<'a> {
 }
 
 struct TokenIterator<'a> {
-    source: &'a str,
+    source: &'a Rc<str>,
     file: &'a str,
     pos: usize,
+    /// Whether to produce comment tokens, rather than skipping them
+    trivia: bool,
+    /// For each interpolated string whose embedded expression is being tokenised,
+    /// the number of `{`s in the expression which haven't been closed
+    interpolations: Vec<usize>,
 }
 
 impl<'a> TokenIterator<'a> {
-    fn new(source: &'a str, file: &'a str) -> Self {
+    fn new(source: &'a Rc<str>, file: &'a str, trivia: bool) -> Self {
         TokenIterator {
             source,
             file,
             pos: 0,
+            trivia,
+            interpolations: vec![],
+        }
+    }
+
+    /// Stop at the error, rather than giving it again on every call.
+    /// The offset is where in the source the tokenising which failed started.
+    /// Invalid escapes know where they are, other errors are at the character
+    /// which couldn't be tokenised
+    fn fail(&mut self, error: TokeniserErrorType, offset: usize) -> TokeniserError<'a> {
+        self.pos = self.source.len();
+        let error_type = error.offset_by(offset);
+        let (start_pos, end_pos) = match error_type {
+            TokeniserErrorType::InvalidEscape { start_pos, end_pos } => (start_pos, end_pos),
+            _ => {
+                let (rest, skipped) = Token::remove_whitespace(&self.source[offset..]);
+                let start_pos = offset + skipped;
+                (
+                    start_pos,
+                    start_pos + rest.chars().next().map_or(0, char::len_utf8),
+                )
+            }
+        };
+        TokeniserError {
+            error_type,
+            location: Location {
+                file: self.file,
+                source: self.source,
+                start_pos,
+                end_pos,
+            },
         }
     }
 }
 
 impl<'a> Iterator for TokenIterator<'a> {
-    type Item = Result<Token<'a>>;
+    type Item = std::result::Result<Token<'a>, TokeniserError<'a>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match Token::get_token_from_string(self.source.get(self.pos..)?) {
-            Ok(Some((t, start, end))) => {
-                let token = Token {
-                    data: t,
-                    location: Location {
-                        file: self.file,
-                        source: self.source,
-                        start_pos: self.pos + start,
-                        end_pos: self.pos + end,
-                    },
-                };
-                self.pos += end;
-                Some(Ok(token))
+        let source = self.source.get(self.pos..)?;
+        let token = if self.trivia {
+            Token::get_token_or_trivia_from_string(source)
+        } else {
+            Token::get_token_from_string(source)
+        };
+        let (mut data, start, mut end) = match token {
+            Ok(Some(token)) => token,
+            Ok(None) => return None,
+            Err(e) => return Some(Err(self.fail(e, self.pos))),
+        };
+        match (&data, self.interpolations.last_mut()) {
+            (TokenData::InterpolationStart(_), _) => self.interpolations.push(0),
+            (TokenData::OpenCurlyBracket, Some(depth)) => *depth += 1,
+            // The brace closing an embedded expression carries on with the string
+            (TokenData::CloseCurlyBracket, Some(0)) => {
+                match Token::match_string_continuation(&source[start..]) {
+                    Ok((continuation, length)) => {
+                        if matches!(continuation, TokenData::InterpolationEnd(_)) {
+                            self.interpolations.pop();
+                        }
+                        data = continuation;
+                        end = start + length;
+                    }
+                    Err(e) => return Some(Err(self.fail(e, self.pos + start))),
+                }
             }
-            Ok(None) => None,
-            Err(e) => Some(Err(e)),
+            (TokenData::CloseCurlyBracket, Some(depth)) => *depth -= 1,
+            _ => (),
         }
+        let token = Token {
+            data,
+            location: Location {
+                file: self.file,
+                source: self.source,
+                start_pos: self.pos + start,
+                end_pos: self.pos + end,
+            },
+        };
+        self.pos += end;
+        Some(Ok(token))
     }
 }
 
+/// How far the end of a piece of text is from its start
 enum Offset {
     Columns(usize),
     LinesAndColumns { lines: usize, columns: usize },
 }
 
-#[derive(Debug, PartialEq)]
-pub enum StringMatch<'a> {
-    Closed(TokenData<'a>, usize),
-    Open,
+impl Offset {
+    fn of(text: &str) -> Offset {
+        match text.rfind('\n') {
+            None => Offset::Columns(text.chars().count()),
+            Some(last_newline) => Offset::LinesAndColumns {
+                lines: text.matches('\n').count(),
+                columns: text[last_newline + 1..].chars().count(),
+            },
+        }
+    }
+
+    /// The line and column the text ends on, both counting from 1
+    fn line_and_column(&self) -> (usize, usize) {
+        match *self {
+            Offset::Columns(columns) => (1, columns + 1),
+            Offset::LinesAndColumns { lines, columns } => (lines + 1, columns + 1),
+        }
+    }
 }
 
 impl<'a> Token<'a> {
     /// Match a single character from a stream
     fn match_single(source: &'a str) -> Option<TokenData<'a>> {
-        match source.get(0..1)? {
-            "`" => Some(TokenData::Apostrophe),
-            "$" => Some(TokenData::Dollar),
-            "|" => Some(TokenData::Pipe),
-            "," => Some(TokenData::Comma),
-            "(" => Some(TokenData::OpenParen),
-            ")" => Some(TokenData::CloseParen),
-            "[" => Some(TokenData::OpenSquareBracket),
-            "]" => Some(TokenData::CloseSquareBracket),
-            "{" => Some(TokenData::OpenCurlyBracket),
-            "}" => Some(TokenData::CloseCurlyBracket),
-            "<" => Some(TokenData::OpenAngleBracket),
-            ">" => Some(TokenData::CloseAngleBracket),
-            ":" => Some(TokenData::Colon),
-            "!" => Some(TokenData::ExclamationMark),
-            "~" => Some(TokenData::Tilde),
-            ";" => Some(TokenData::SemiColon),
+        Self::single_character(source.chars().next()?)
+    }
+
+    /// The token made of just this character, if there is one
+    fn single_character(c: char) -> Option<TokenData<'a>> {
+        match c {
+            '`' => Some(TokenData::Apostrophe),
+            '$' => Some(TokenData::Dollar),
+            '|' => Some(TokenData::Pipe),
+            ',' => Some(TokenData::Comma),
+            '(' => Some(TokenData::OpenParen),
+            ')' => Some(TokenData::CloseParen),
+            '[' => Some(TokenData::OpenSquareBracket),
+            ']' => Some(TokenData::CloseSquareBracket),
+            '{' => Some(TokenData::OpenCurlyBracket),
+            '}' => Some(TokenData::CloseCurlyBracket),
+            '<' => Some(TokenData::OpenAngleBracket),
+            '>' => Some(TokenData::CloseAngleBracket),
+            ':' => Some(TokenData::Colon),
+            '!' => Some(TokenData::ExclamationMark),
+            '~' => Some(TokenData::Tilde),
+            '&' => Some(TokenData::Ampersand),
+            '@' => Some(TokenData::At),
+            '.' => Some(TokenData::Dot),
+            '^' => Some(TokenData::Caret),
+            ';' => Some(TokenData::SemiColon),
             _ => None,
         }
     }
@@ -153,6 +364,17 @@ impl<'a> Token<'a> {
             "let" => Some(TokenData::Let),
             "letrec" => Some(TokenData::LetRec),
             "extract" => Some(TokenData::Extract),
+            "with" => Some(TokenData::With),
+            "match" => Some(TokenData::Match),
+            "import" => Some(TokenData::Import),
+            "module" => Some(TokenData::Module),
+            "behaviour" => Some(TokenData::Behaviour),
+            "yield" => Some(TokenData::Yield),
+            "spawn" => Some(TokenData::Spawn),
+            "launch" => Some(TokenData::Launch),
+            "raise" => Some(TokenData::Raise),
+            "try" => Some(TokenData::Try),
+            "catch" => Some(TokenData::Catch),
             "else" => Some(TokenData::Else),
             "cond" => Some(TokenData::Cond),
             "true" => Some(TokenData::True),
@@ -162,43 +384,205 @@ impl<'a> Token<'a> {
         }
     }
 
-    fn match_string(source: &'a str) -> Result<Option<(&str, usize)>> {
-        match source.get(0..1) {
-            Some("\"") => {
-                let mut offset = 0;
-                loop {
-                    if let Some(c) = source.get(offset + 1..offset + 2) {
-                        if c == "\"" && source.get(offset..offset + 1).unwrap() != "\\" {
-                            break;
-                        } else {
-                            offset += 1
-                        }
+    /// Match a string literal, giving its value with escapes decoded.
+    /// Strings are `"..."`, raw strings without escapes are `r"..."` (or `r#"..."#`
+    /// with any number of `#`s, to contain quotes), and multi-line strings are `"""..."""`.
+    /// A `{` in a `"..."` string starts an embedded expression, giving an `InterpolationStart`
+    fn match_string(source: &'a str) -> Result<Option<(TokenData<'a>, usize)>> {
+        if source.starts_with("\"\"\"") {
+            let end = Self::find_string_end(source, 3, "\"\"\"")?;
+            let value = Self::strip_indentation(&source[3..end], 3)?;
+            Ok(Some((TokenData::String(value.into()), end + 3)))
+        } else if source.starts_with('"') {
+            let (value, length, interpolated) = Self::match_string_part(source)?;
+            if interpolated {
+                Ok(Some((TokenData::InterpolationStart(value), length)))
+            } else {
+                Ok(Some((TokenData::String(value), length)))
+            }
+        } else if let Some(rest) = source.strip_prefix('r') {
+            let hashes = rest.len() - rest.trim_start_matches('#').len();
+            if rest[hashes..].starts_with('"') {
+                let close = format!("\"{}", &rest[..hashes]);
+                let start = hashes + 2;
+                let length = source[start..]
+                    .find(&close)
+                    .ok_or(TokeniserErrorType::OpenString)?;
+                Ok(Some((
+                    TokenData::String(source[start..start + length].into()),
+                    start + length + close.len(),
+                )))
+            } else {
+                Ok(None)
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Match the rest of an interpolated string, from the `}` closing an embedded expression
+    fn match_string_continuation(source: &'a str) -> Result<(TokenData<'a>, usize)> {
+        let (value, length, interpolated) = Self::match_string_part(source)?;
+        if interpolated {
+            Ok((TokenData::InterpolationMiddle(value), length))
+        } else {
+            Ok((TokenData::InterpolationEnd(value), length))
+        }
+    }
+
+    /// Match the text after the first character of the source, up to the closing quote
+    /// or the `{` of an embedded expression. Gives the decoded text, the length including
+    /// the quote or brace, and whether it was a brace
+    fn match_string_part(source: &'a str) -> Result<(Cow<'a, str>, usize, bool)> {
+        let mut i = 1;
+        while let Some(c) = source[i..].chars().next() {
+            match c {
+                '"' | '{' => {
+                    let contents = &source[1..i];
+                    let value = if contents.contains('\\') {
+                        let mut value = String::new();
+                        Self::unescape(contents, 1, &mut value)?;
+                        value.into()
                     } else {
-                        return Err(TokeniserError::OpenString);
-                    }
+                        contents.into()
+                    };
+                    return Ok((value, i + 1, c == '{'));
                 }
-                Ok(Some((&source[1..offset + 1], offset + 2)))
+                // Escapes are skipped whole, so that the brace of a unicode escape
+                // doesn't start an embedded expression
+                '\\' if source.len() > i + 1 => {
+                    let escape = &source[i..];
+                    i += Self::match_escape(escape)
+                        .ok_or_else(|| Self::invalid_escape(escape, i))?
+                        .1;
+                }
+                _ => i += c.len_utf8(),
             }
-            _ => Ok(None),
         }
+        Err(TokeniserErrorType::OpenString)
     }
 
-    fn match_symbol(source: &'a str) -> Option<(&str, usize)> {
-        let mut offset = 0;
-        loop {
-            if let Some(s) = source.get(offset..) {
-                if Self::match_single(s).is_some()
-                    || Self::is_whitespace(s)
-                    || s.get(0..1).map(|c| c == "\"").unwrap_or(true)
-                {
-                    break;
-                } else {
-                    offset += 1;
+    /// The offset of the closing delimiter of a string whose contents start at `start`,
+    /// skipping over escaped characters
+    fn find_string_end(source: &'a str, start: usize, close: &str) -> Result<usize> {
+        let mut chars = source[start..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            if source[start + i..].starts_with(close) {
+                return Ok(start + i);
+            } else if c == '\\' {
+                chars.next();
+            }
+        }
+        Err(TokeniserErrorType::OpenString)
+    }
+
+    /// Decode the escape at the start of the source, which starts with a backslash,
+    /// returning the character and the length of the escape
+    fn match_escape(source: &'a str) -> Option<(char, usize)> {
+        let c = match source[1..].chars().next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' => '\\',
+            '"' => '"',
+            '{' => '{',
+            '}' => '}',
+            'u' => {
+                let digits = source[2..].strip_prefix('{')?;
+                let length = digits
+                    .find(|c: char| !c.is_ascii_hexdigit())
+                    .unwrap_or(digits.len());
+                if !(1..=6).contains(&length) || !digits[length..].starts_with('}') {
+                    return None;
                 }
-            } else {
-                break;
+                let c = char::from_u32(u32::from_str_radix(&digits[..length], 16).ok()?)?;
+                return Some((c, length + 4));
             }
+            _ => return None,
+        };
+        Some((c, 2))
+    }
+
+    /// The error for an escape which couldn't be decoded, starting at `pos`.
+    /// Unicode escapes are covered up to their closing brace
+    fn invalid_escape(source: &'a str, pos: usize) -> TokeniserErrorType {
+        let mut length = 1 + source[1..].chars().next().map_or(0, char::len_utf8);
+        if let Some(rest) = source[1..].strip_prefix("u{") {
+            let n = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            length = 3 + n + usize::from(rest[n..].starts_with('}'));
         }
+        TokeniserErrorType::InvalidEscape {
+            start_pos: pos,
+            end_pos: pos + length,
+        }
+    }
+
+    /// Decode the escapes in part of a string, starting at `offset` in the token,
+    /// onto the end of the value
+    fn unescape(contents: &'a str, offset: usize, value: &mut String) -> Result<()> {
+        let mut rest = contents;
+        while let Some(i) = rest.find('\\') {
+            value.push_str(&rest[..i]);
+            let escape = &rest[i..];
+            let pos = offset + contents.len() - escape.len();
+            let (c, length) =
+                Self::match_escape(escape).ok_or_else(|| Self::invalid_escape(escape, pos))?;
+            value.push(c);
+            rest = &escape[length..];
+        }
+        value.push_str(rest);
+        Ok(())
+    }
+
+    /// Decode a multi-line string, starting at `offset` in the token. The newline after
+    /// the opening quotes, the line the closing quotes are on if it's blank,
+    /// and the indentation common to every line which isn't blank are all removed
+    fn strip_indentation(contents: &'a str, offset: usize) -> Result<String> {
+        let (contents, offset) = match contents.find('\n') {
+            Some(i) if contents[..i].trim().is_empty() => (&contents[i + 1..], offset + i + 1),
+            _ => (contents, offset),
+        };
+        let mut lines = vec![];
+        let mut line_offset = offset;
+        for line in contents.split('\n') {
+            lines.push((line_offset, line.strip_suffix('\r').unwrap_or(line)));
+            line_offset += line.len() + 1;
+        }
+        if lines.len() > 1 && lines.last().is_some_and(|(_, l)| l.trim().is_empty()) {
+            lines.pop();
+        }
+        let indentation = lines
+            .iter()
+            .filter(|(_, l)| !l.trim().is_empty())
+            .map(|(_, l)| l.len() - l.trim_start_matches([' ', '\t']).len())
+            .min()
+            .unwrap_or(0);
+        let mut value = String::new();
+        for (i, (line_offset, line)) in lines.into_iter().enumerate() {
+            if i > 0 {
+                value.push('\n');
+            }
+            if !line.trim().is_empty() {
+                Self::unescape(&line[indentation..], line_offset + indentation, &mut value)?;
+            }
+        }
+        Ok(value)
+    }
+
+    /// Match a symbol, which runs until whitespace, a single character token,
+    /// a comment or a string. Symbols can contain any other unicode characters
+    fn match_symbol(source: &'a str) -> Option<(&'a str, usize)> {
+        let offset = source
+            .find(|c: char| {
+                Self::single_character(c).is_some()
+                    || Self::is_whitespace(c)
+                    || c == '#'
+                    || c == '"'
+            })
+            .unwrap_or(source.len());
         if offset == 0 {
             None
         } else {
@@ -207,24 +591,9 @@ impl<'a> Token<'a> {
     }
 
     fn match_digits(source: &'a str) -> Option<usize> {
-        fn is_digit(s: &str) -> bool {
-            match s {
-                "0" | "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9" => true,
-                _ => false,
-            }
-        }
-        let mut offset = 0;
-        loop {
-            if let Some(d) = source.get(offset..offset + 1) {
-                if is_digit(d) {
-                    offset += 1;
-                } else {
-                    break;
-                }
-            } else {
-                break;
-            }
-        }
+        let offset = source
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(source.len());
         if offset == 0 {
             None
         } else {
@@ -233,69 +602,106 @@ impl<'a> Token<'a> {
     }
 
     fn match_sign(source: &'a str) -> bool {
-        match source.get(0..1) {
-            Some("+") | Some("-") => true,
-            _ => false,
-        }
+        source.starts_with(['+', '-'])
     }
 
     fn match_exponent(source: &'a str) -> Option<usize> {
-        let mut offset = 0;
-        match source.get(0..1)? {
-            "e" | "E" => {
-                offset += 1;
-                if Self::match_sign(source.get(offset..)?) {
-                    offset += 1;
-                }
-                Some(offset + Self::match_digits(source.get(offset..)?)?)
-            }
-            _ => None,
-        }
+        let rest = source.strip_prefix(['e', 'E'])?;
+        let sign = usize::from(Self::match_sign(rest));
+        Some(1 + sign + Self::match_digits(&rest[sign..])?)
     }
 
-    fn match_number(source: &'a str) -> Option<(&str, usize)> {
+    /// Match an int, or a float if there's a decimal point or exponent
+    fn match_number(source: &'a str) -> Option<(TokenData<'a>, usize)> {
         let mut offset = 0;
         if Self::match_sign(source) {
             offset += 1;
         };
-        let n = Self::match_digits(&source.get(offset..)?)?;
-        offset += n;
-        if source.get(offset..offset + 1) == Some(".") {
+        offset += Self::match_digits(&source[offset..])?;
+        let integer_end = offset;
+        if source[offset..].starts_with('.') {
             offset += 1;
-            if let Some(n) = &source.get(offset..).and_then(|x| Self::match_digits(x)) {
+            if let Some(n) = Self::match_digits(&source[offset..]) {
                 offset += n;
             }
         }
-        if let Some(n) = source.get(offset..).and_then(|x| Self::match_exponent(x)) {
+        if let Some(n) = Self::match_exponent(&source[offset..]) {
             offset += n;
         };
-        Some((&source[..offset], offset))
+        if offset == integer_end {
+            Some((TokenData::Int(&source[..offset]), offset))
+        } else {
+            Some((TokenData::Float(&source[..offset]), offset))
+        }
     }
 
-    /// Returns true if the next character in the string is whitespace
-    fn is_whitespace(source: &'a str) -> bool {
-        if let Some(c) = source.get(0..1) {
-            match c {
-                "\t" | "\n" | "\r" | " " => true,
-                _ => false,
+    fn is_whitespace(c: char) -> bool {
+        c.is_whitespace()
+    }
+
+    /// Match a line comment (`#` to the end of the line) or a block comment
+    /// (`#| ... |#`, which can contain other block comments), returning its length
+    fn match_comment(source: &'a str) -> Result<Option<usize>> {
+        if source.starts_with("#|") {
+            let mut depth = 0;
+            let mut offset = 0;
+            loop {
+                let rest = &source[offset..];
+                if rest.starts_with("#|") {
+                    depth += 1;
+                    offset += 2;
+                } else if rest.starts_with("|#") {
+                    depth -= 1;
+                    offset += 2;
+                    if depth == 0 {
+                        return Ok(Some(offset));
+                    }
+                } else if let Some(c) = rest.chars().next() {
+                    offset += c.len_utf8();
+                } else {
+                    return Err(TokeniserErrorType::OpenComment);
+                }
             }
+        } else if source.starts_with('#') {
+            Ok(Some(source.find('\n').unwrap_or(source.len())))
         } else {
-            false
+            Ok(None)
         }
     }
 
-    /// Skip all forms of whitespace in the source until the next non-whitespace character,
-    /// and return a tuple of (string after skipping, chars skipped)
+    /// Skip whitespace characters, returning a tuple of (string after skipping, bytes skipped)
+    fn remove_spaces(source: &'a str) -> (&'a str, usize) {
+        let rest = source.trim_start_matches(Self::is_whitespace);
+        (rest, source.len() - rest.len())
+    }
+
+    /// Skip all forms of whitespace and comments in the source until the next token,
+    /// and return a tuple of (string after skipping, bytes skipped).
+    /// Unclosed block comments aren't skipped, so they can be reported as errors
     fn remove_whitespace(source: &'a str) -> (&'a str, usize) {
         let mut offset = 0;
-        while source
-            .get(offset..offset + 1)
-            .map(|s| Self::is_whitespace(s))
-            .unwrap_or(false)
-        {
-            offset += 1;
+        loop {
+            offset += Self::remove_spaces(&source[offset..]).1;
+            match Self::match_comment(&source[offset..]) {
+                Ok(Some(length)) => offset += length,
+                _ => return (&source[offset..], offset),
+            }
+        }
+    }
+
+    /// Like `get_token_from_string`, but comments are given as tokens instead of skipped
+    fn get_token_or_trivia_from_string(
+        source: &'a str,
+    ) -> Result<Option<(TokenData<'a>, usize, usize)>> {
+        let (s, start_offset) = Self::remove_spaces(source);
+        match Self::match_comment(s)? {
+            Some(length) => Ok(Some((
+                TokenData::Comment(&s[..length]),
+                start_offset,
+                start_offset + length,
+            ))),
+            None => Self::get_token_from_string(source),
         }
-        (source.get(offset..).unwrap(), offset)
     }
 
     /// Get the next token as a string, returning a tuple of
@@ -304,17 +710,24 @@ impl<'a> Token<'a> {
         let (s, start_offset) = Self::remove_whitespace(source);
         if s.is_empty() {
             Ok(None)
+        } else if s.starts_with("#|") {
+            // The only comments remove_whitespace leaves are block comments which aren't closed
+            Err(TokeniserErrorType::OpenComment)
+        } else if s.starts_with("..") {
+            Ok(Some((TokenData::DotDot, start_offset, start_offset + 2)))
         } else if let Some(s) = Self::match_single(s) {
             Ok(Some((s, start_offset, start_offset + 1)))
-        } else if let Some((string_token, string_offset)) = Self::match_string(s)? {
+        } else if let Some((string_token, string_offset)) =
+            Self::match_string(s).map_err(|e| e.offset_by(start_offset))?
+        {
             Ok(Some((
-                TokenData::String(string_token),
+                string_token,
                 start_offset,
                 start_offset + string_offset,
             )))
         } else if let Some((number_token, string_offset)) = Self::match_number(s) {
             Ok(Some((
-                TokenData::Number(number_token),
+                number_token,
                 start_offset,
                 start_offset + string_offset,
             )))
@@ -329,25 +742,32 @@ impl<'a> Token<'a> {
                 )))
             }
         } else {
-            Err(TokeniserError::NoMatch)
+            Err(TokeniserErrorType::NoMatch)
         }
     }
 
     pub fn tokenise_source(
-        source: &'a str,
+        source: &'a Rc<str>,
         file: &'a str,
-    ) -> impl Iterator<Item = Result<Token<'a>>> {
-        TokenIterator {
-            source,
-            file,
-            pos: 0,
-        }
+    ) -> impl Iterator<Item = std::result::Result<Token<'a>, TokeniserError<'a>>> {
+        TokenIterator::new(source, file, false)
+    }
+
+    /// Tokenise the source, keeping comments as `Comment` tokens for tools which need them
+    #[allow(dead_code)]
+    pub fn tokenise_source_with_trivia(
+        source: &'a Rc<str>,
+        file: &'a str,
+    ) -> impl Iterator<Item = std::result::Result<Token<'a>, TokeniserError<'a>>> {
+        TokenIterator::new(source, file, true)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::tokeniser::{StringMatch, TokenData, TokeniserError};
+    use std::rc::Rc;
+
+    use crate::tokeniser::{TokenData, TokeniserErrorType};
 
     use super::Token;
 
@@ -360,29 +780,225 @@ mod test {
         assert_eq!(Token::remove_whitespace(" b c "), ("b c ", 1));
     }
 
+    #[test]
+    fn comments_are_skipped() {
+        assert_eq!(Token::remove_whitespace("# a comment\n  hi"), ("hi", 14));
+        assert_eq!(
+            Token::remove_whitespace("#| a #| nested |# one |# hi"),
+            ("hi", 25)
+        );
+        assert_eq!(Token::remove_whitespace("#| open"), ("#| open", 0));
+        assert_eq!(
+            Token::get_token_from_string(" #| open"),
+            Err(TokeniserErrorType::OpenComment)
+        );
+        let source: Rc<str> = "a # one\n#| two |# b#c".into();
+        let tokens = Token::tokenise_source(&source, "")
+            .map(|t| t.map(|t| (t.data, t.location.start_pos, t.location.end_pos)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenData::Symbol("a"), 0, 1),
+                (TokenData::Symbol("b"), 18, 19)
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_are_kept_as_trivia() {
+        let source: Rc<str> = "a # one\n#| two |# b".into();
+        let tokens = Token::tokenise_source_with_trivia(&source, "")
+            .map(|t| t.map(|t| (t.data, t.location.start_pos)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenData::Symbol("a"), 0),
+                (TokenData::Comment("# one"), 2),
+                (TokenData::Comment("#| two |#"), 8),
+                (TokenData::Symbol("b"), 18)
+            ]
+        );
+    }
+
     #[test]
     fn match_string_works() {
-        assert_eq!(Token::match_string("\"this\""), Ok(Some(("\"this\"", 6))));
+        assert_eq!(
+            Token::match_string("\"this\""),
+            Ok(Some((TokenData::String("this".into()), 6)))
+        );
         assert_eq!(
             Token::match_string("\"open"),
-            Err(TokeniserError::OpenString)
+            Err(TokeniserErrorType::OpenString)
         );
         assert_eq!(
             Token::match_string("\"\\\"hi\\\"\""),
-            Ok(Some(("\"\\\"hi\\\"\"", 8)))
+            Ok(Some((TokenData::String("\"hi\"".into()), 8)))
+        );
+        assert_eq!(
+            Token::match_string("\"ü\\\\\" x"),
+            Ok(Some((TokenData::String("ü\\".into()), 6)))
         );
         assert_eq!(Token::match_string("none"), Ok(None));
     }
 
+    #[test]
+    fn escapes_are_decoded() {
+        assert_eq!(
+            Token::match_string(r#""a\tb\nc\r\0\u{1F600}\u{e9}""#),
+            Ok(Some((TokenData::String("a\tb\nc\r\0😀é".into()), 28)))
+        );
+        assert_eq!(
+            Token::get_token_from_string(r#"  "ok \q""#),
+            Err(TokeniserErrorType::InvalidEscape {
+                start_pos: 6,
+                end_pos: 8
+            })
+        );
+        for (string, end_pos) in [
+            (r#""\u{zz} ""#, 7),
+            (r#""\u{110000}""#, 11),
+            (r#""\u{}""#, 5),
+            (r#""\u20""#, 3),
+        ] {
+            assert_eq!(
+                Token::match_string(string),
+                Err(TokeniserErrorType::InvalidEscape {
+                    start_pos: 1,
+                    end_pos
+                })
+            );
+        }
+        let source: Rc<str> = "x \"\\!\"".into();
+        let error = Token::tokenise_source(&source, "")
+            .find_map(|t| t.err())
+            .unwrap();
+        assert_eq!(
+            error.error_type,
+            TokeniserErrorType::InvalidEscape {
+                start_pos: 3,
+                end_pos: 5
+            }
+        );
+        assert_eq!((error.location.start_pos, error.location.end_pos), (3, 5));
+    }
+
+    #[test]
+    fn raw_strings_keep_backslashes() {
+        assert_eq!(
+            Token::match_string(r#"r"a\n" x"#),
+            Ok(Some((TokenData::String(r"a\n".into()), 6)))
+        );
+        assert_eq!(
+            Token::match_string(r###"r##"say "#hi"#"## x"###),
+            Ok(Some((TokenData::String(r##"say "#hi"#"##.into()), 17)))
+        );
+        assert_eq!(
+            Token::match_string(r#"r#"open""#),
+            Err(TokeniserErrorType::OpenString)
+        );
+        assert_eq!(Token::match_string("r x"), Ok(None));
+        assert_eq!(
+            Token::get_token_from_string("r"),
+            Ok(Some((TokenData::Symbol("r"), 0, 1)))
+        );
+    }
+
+    #[test]
+    fn multi_line_strings_strip_indentation() {
+        let source = "\"\"\"\n    first\n      \"second\"\n\n    \\tthird\n    \"\"\"";
+        assert_eq!(
+            Token::match_string(source),
+            Ok(Some((
+                TokenData::String("first\n  \"second\"\n\n\tthird".into()),
+                source.len()
+            )))
+        );
+        assert_eq!(
+            Token::match_string("\"\"\"one line\"\"\""),
+            Ok(Some((TokenData::String("one line".into()), 14)))
+        );
+        assert_eq!(
+            Token::match_string("\"\"\"\n  a\n  \\x\"\"\""),
+            Err(TokeniserErrorType::InvalidEscape {
+                start_pos: 10,
+                end_pos: 12
+            })
+        );
+        assert_eq!(
+            Token::match_string("\"\"\"open\"\""),
+            Err(TokeniserErrorType::OpenString)
+        );
+    }
+
+    #[test]
+    fn interpolated_strings_are_split_into_parts() {
+        use TokenData as T;
+        let source: Rc<str> = r#""total: {n `+ {1}}, \{ {"in{"ner"}"}!""#.into();
+        let tokens = Token::tokenise_source(&source, "")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|t| (&t.data, &source[t.location.start_pos..t.location.end_pos]))
+                .collect::<Vec<_>>(),
+            vec![
+                (&T::InterpolationStart("total: ".into()), "\"total: {"),
+                (&T::Symbol("n"), "n"),
+                (&T::Apostrophe, "`"),
+                (&T::Symbol("+"), "+"),
+                (&T::OpenCurlyBracket, "{"),
+                (&T::Int("1"), "1"),
+                (&T::CloseCurlyBracket, "}"),
+                (&T::InterpolationMiddle(", { ".into()), r"}, \{ {"),
+                (&T::InterpolationStart("in".into()), "\"in{"),
+                (&T::String("ner".into()), "\"ner\""),
+                (&T::InterpolationEnd("".into()), "}\""),
+                (&T::InterpolationEnd("!".into()), "}!\""),
+            ]
+        );
+        // The rest of the string is unclosed, so the error is at the brace it carries on from
+        let source: Rc<str> = "\"a{b}c".into();
+        let error = Token::tokenise_source(&source, "")
+            .find_map(|t| t.err())
+            .unwrap();
+        assert_eq!(error.error_type, TokeniserErrorType::OpenString);
+        assert_eq!((error.location.start_pos, error.location.end_pos), (4, 5));
+    }
+
     #[test]
     fn match_symbol_works() {
         assert_eq!(Token::match_symbol("sym"), Some(("sym", 3)));
         assert_eq!(Token::match_symbol("sym`"), Some(("sym", 3)));
         assert_eq!(Token::match_symbol("sym  "), Some(("sym", 3)));
         assert_eq!(Token::match_symbol("sym\""), Some(("sym", 3)));
+        assert_eq!(Token::match_symbol("m.a"), Some(("m", 1)));
         assert_eq!(Token::match_symbol(""), None);
     }
 
+    #[test]
+    fn unicode_is_tokenised_by_character() {
+        assert_eq!(Token::match_symbol("λx·y z"), Some(("λx·y", 6)));
+        assert_eq!(Token::match_symbol("café\"s\""), Some(("café", 5)));
+        assert_eq!(Token::remove_whitespace("\u{a0}\u{3000}ü"), ("ü", 5));
+        let source: Rc<str> = "let naïve \"🙂 ok\", über ñ`+1".into();
+        let tokens = Token::tokenise_source(&source, "")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|t| &source[t.location.start_pos..t.location.end_pos])
+                .collect::<Vec<_>>(),
+            vec!["let", "naïve", "\"🙂 ok\"", ",", "über", "ñ", "`", "+1"]
+        );
+        assert_eq!(tokens[2].data, TokenData::String("🙂 ok".into()));
+    }
+
     #[test]
     fn match_digits_works() {
         assert_eq!(Token::match_digits("123"), Some(3));
@@ -392,15 +1008,39 @@ mod test {
 
     #[test]
     fn match_decimal_works() {
-        assert_eq!(Token::match_number("2.3"), Some(("2.3", 3)));
-        assert_eq!(Token::match_number("-2.3"), Some(("-2.3", 4)));
-        assert_eq!(Token::match_number("101202 "), Some(("101202", 6)));
+        assert_eq!(
+            Token::match_number("2.3"),
+            Some((TokenData::Float("2.3"), 3))
+        );
+        assert_eq!(
+            Token::match_number("-2.3"),
+            Some((TokenData::Float("-2.3"), 4))
+        );
+        assert_eq!(
+            Token::match_number("101202 "),
+            Some((TokenData::Int("101202"), 6))
+        );
         assert_eq!(Token::match_number(" "), None);
-        assert_eq!(Token::match_number("-2.e-10"), Some(("-2.e-10", 7)));
-        assert_eq!(Token::match_number("-2.E10"), Some(("-2.E10", 6)));
-        assert_eq!(Token::match_number("-2.e+1"), Some(("-2.e+1", 6)));
-        assert_eq!(Token::match_number("-2e1"), Some(("-2e1", 4)));
-        assert_eq!(Token::match_number("+2.1e+10"), Some(("+2.1e+10", 8)));
+        assert_eq!(
+            Token::match_number("-2.e-10"),
+            Some((TokenData::Float("-2.e-10"), 7))
+        );
+        assert_eq!(
+            Token::match_number("-2.E10"),
+            Some((TokenData::Float("-2.E10"), 6))
+        );
+        assert_eq!(
+            Token::match_number("-2.e+1"),
+            Some((TokenData::Float("-2.e+1"), 6))
+        );
+        assert_eq!(
+            Token::match_number("-2e1"),
+            Some((TokenData::Float("-2e1"), 4))
+        );
+        assert_eq!(
+            Token::match_number("+2.1e+10"),
+            Some((TokenData::Float("+2.1e+10"), 8))
+        );
     }
 
     #[test]
@@ -415,15 +1055,19 @@ mod test {
         );
         assert_eq!(
             Token::get_token_from_string(" \"this\" "),
-            Ok(Some((TokenData::String("\"this\""), 1, 7)))
+            Ok(Some((TokenData::String("this".into()), 1, 7)))
         );
         assert_eq!(
             Token::get_token_from_string("\n\n0.3e10 "),
-            Ok(Some((TokenData::Number("0.3e10"), 2, 8)))
+            Ok(Some((TokenData::Float("0.3e10"), 2, 8)))
+        );
+        assert_eq!(
+            Token::get_token_from_string(" ..rest"),
+            Ok(Some((TokenData::DotDot, 1, 3)))
         );
         assert_eq!(
             Token::get_token_from_string("\"open"),
-            Err(TokeniserError::OpenString)
+            Err(TokeniserErrorType::OpenString)
         )
     }
 
@@ -431,7 +1075,7 @@ mod test {
     fn tokenise_source_works() {
         use TokenData as T;
         assert_eq!(
-            Token::tokenise_source("|x y|{x `* -2.0}", "")
+            Token::tokenise_source(&"|x y|{x `* -2.0}".into(), "")
                 .map(|x| x.unwrap().data)
                 .collect::<Vec<_>>(),
             vec![
@@ -443,25 +1087,25 @@ mod test {
                 T::Symbol("x"),
                 T::Apostrophe,
                 T::Symbol("*"),
-                T::Number("-2.0"),
+                T::Float("-2.0"),
                 T::CloseCurlyBracket
             ]
         );
         assert_eq!(
-            Token::tokenise_source("let x 2, y cond {true ~ \"3\", else nil\n}", "")
+            Token::tokenise_source(&"let x 2, y cond {true ~ \"3\", else nil\n}".into(), "")
                 .map(|x| x.unwrap().data)
                 .collect::<Vec<_>>(),
             vec![
                 T::Let,
                 T::Symbol("x"),
-                T::Number("2"),
+                T::Int("2"),
                 T::Comma,
                 T::Symbol("y"),
                 T::Cond,
                 T::OpenCurlyBracket,
                 T::True,
                 T::Tilde,
-                T::String("\"3\""),
+                T::String("3".into()),
                 T::Comma,
                 T::Else,
                 T::Nil,
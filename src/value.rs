@@ -1,28 +1,82 @@
-use im::HashMap;
-use im::Vector;
-use std::cell::Ref;
-use std::fmt::Debug;
+use im::{HashMap, Vector};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::{Debug, Display};
+use std::hash::{BuildHasherDefault, Hash, Hasher};
 use std::{cell::RefCell, rc::Rc};
 
+use crate::behaviour::Behaviour;
+use crate::coroutine::{Coroutine, Launch};
 use crate::native_function::NativeFunction;
-use crate::{expression::Literal, opcode::OpCode};
+use crate::number;
+use crate::symbol::InternedSymbol;
+use crate::{
+    expression::{Expression, Literal, LocatedExpression},
+    opcode::OpCode,
+    tokeniser::Span,
+};
 
 #[derive(Debug, Clone)]
 pub enum ValueError {
     NotANumber,
-    NotAClosure,
     NotAList,
+    NotADictionary,
+    NotABox,
+    NotConstant,
+    NotACollection,
     TooManyArguments,
-    NoNativeSymbol,
+    NotABehaviour,
+    NotACoroutine,
+    /// Only ints can be used here, not floats
+    NotAnInt,
+    /// The result of the int operation doesn't fit in an int
+    Overflow,
+    DivisionByZero,
+    /// The implementation has a method the behaviour doesn't declare
+    UnknownMethod,
+    /// NaN isn't equal to itself, so a key containing it could never be found again
+    NaNKey,
+}
+
+impl Display for ValueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ValueError::NotANumber => "expected a number",
+            ValueError::NotAList => "expected a list",
+            ValueError::NotADictionary => "expected a dictionary",
+            ValueError::NotABox => "expected a box",
+            ValueError::NotConstant => "expected a constant literal",
+            ValueError::NotACollection => "expected a list, dictionary or string",
+            ValueError::TooManyArguments => "the function was given too many arguments",
+            ValueError::NotABehaviour => "expected a behaviour",
+            ValueError::NotACoroutine => "expected a coroutine",
+            ValueError::NotAnInt => "expected an int, not a float",
+            ValueError::Overflow => "the result doesn't fit in an int",
+            ValueError::DivisionByZero => "division by zero",
+            ValueError::UnknownMethod => "the behaviour doesn't declare the method",
+            ValueError::NaNKey => "dictionary keys can't contain NaN",
+        })
+    }
 }
 
 type Result<Ok> = std::result::Result<Ok, ValueError>;
 
+/// A persistent dictionary. The hasher is fixed so that iteration order
+/// (and so the output of `keys` and `values`) is the same between runs
+pub type Dictionary = HashMap<Value, Value, BuildHasherDefault<DefaultHasher>>;
+
 #[derive(Debug, PartialEq)]
 pub struct Function {
     pub opcodes: Vec<OpCode>,
     pub constants: Vec<Value>,
     pub functions: Vec<Rc<Function>>,
+    /// The source spans referred to by opcodes which can fail
+    pub spans: Vec<Span>,
+    /// The span of the expression each opcode was compiled from, for reporting errors
+    pub locations: Vec<Option<Span>>,
+    /// The symbol the function was bound to when it was created, for stack traces
+    pub name: Option<Rc<str>>,
     pub arity: usize,
     pub num_captures: usize,
     pub num_registers: usize,
@@ -72,10 +126,87 @@ impl Closure {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// A record whose members are laid out at compile time
+#[derive(Clone, PartialEq, Hash)]
+pub struct Module {
+    /// The symbol naming each slot
+    pub names: Vector<Value>,
+    pub values: Vector<Value>,
+}
+
+impl Module {
+    /// Look up a member by its symbol
+    pub fn get(&self, name: &Value) -> Option<&Value> {
+        self.names
+            .index_of(name)
+            .and_then(|slot| self.values.get(slot))
+    }
+}
+
+impl Debug for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("module {")?;
+        for (i, (k, v)) in self.names.iter().zip(self.values.iter()).enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_fmt(format_args!("{:?}: {:?}", k, v))?;
+        }
+        f.write_str("}")
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Object {
     Closure(Rc<Closure>),
     String(Rc<String>),
+    /// A mutable cell. Boxes are only equal to themselves
+    Box(Rc<RefCell<Value>>),
+    Module(Rc<Module>),
+    /// Behaviours are only equal to themselves
+    Behaviour(Rc<Behaviour>),
+    /// A method of a behaviour, which dispatches on the type of its first argument
+    Method(Rc<Behaviour>, InternedSymbol),
+    /// Coroutines and launch scopes are only equal to themselves
+    Coroutine(Rc<Coroutine>),
+    Launch(Rc<Launch>),
+}
+
+impl PartialEq for Object {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Object::Closure(a), Object::Closure(b)) => a == b,
+            (Object::String(a), Object::String(b)) => a == b,
+            (Object::Box(a), Object::Box(b)) => Rc::ptr_eq(a, b),
+            (Object::Module(a), Object::Module(b)) => a == b,
+            (Object::Behaviour(a), Object::Behaviour(b)) => Rc::ptr_eq(a, b),
+            (Object::Method(a, m), Object::Method(b, n)) => Rc::ptr_eq(a, b) && m == n,
+            (Object::Coroutine(a), Object::Coroutine(b)) => Rc::ptr_eq(a, b),
+            (Object::Launch(a), Object::Launch(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Hash for Object {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            // Closures compare structurally, so there is nothing cheap to hash
+            // that agrees with equality. They all share a bucket instead.
+            Object::Closure(_) => (),
+            Object::String(s) => s.hash(state),
+            Object::Box(b) => Rc::as_ptr(b).hash(state),
+            Object::Module(m) => m.hash(state),
+            Object::Behaviour(b) => Rc::as_ptr(b).hash(state),
+            Object::Method(b, m) => {
+                Rc::as_ptr(b).hash(state);
+                m.hash(state);
+            }
+            Object::Coroutine(c) => Rc::as_ptr(c).hash(state),
+            Object::Launch(l) => Rc::as_ptr(l).hash(state),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -95,12 +226,18 @@ impl Placeholder {
 
 #[derive(Clone, PartialEq)]
 pub enum Value {
-    Number(f64),
+    Int(i64),
+    /// An integer too big to fit into an int
+    BigInt(Rc<BigInt>),
+    /// A fraction which isn't a whole number
+    Rational(Rc<BigRational>),
+    Float(f64),
     Bool(bool),
     Nil,
     Uninit,
+    Symbol(InternedSymbol),
     List(Vector<Value>),
-    // Dictionary(HashMap<Value, Value>),
+    Dictionary(Dictionary),
     NativeFunction(NativeFunction),
     Object(Object),
 }
@@ -108,36 +245,172 @@ pub enum Value {
 impl Debug for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Value::Number(n) => f.write_fmt(format_args!("{:?}", n)),
+            Value::Int(n) => f.write_fmt(format_args!("{:?}", n)),
+            Value::BigInt(n) => f.write_fmt(format_args!("{}", n)),
+            Value::Rational(n) => f.write_fmt(format_args!("{}", n)),
+            Value::Float(n) => f.write_fmt(format_args!("{:?}", n)),
             Value::Bool(b) => f.write_fmt(format_args!("{:?}", b)),
             Value::Nil => f.write_fmt(format_args!("nil")),
             Value::Uninit => f.write_str("Uninit"),
+            Value::Symbol(s) => s.fmt(f),
             Value::List(l) => f.write_fmt(format_args!("{:?}", l)),
-            // Value::Dictionary(_) => todo!(),
+            Value::Dictionary(d) => {
+                f.write_str("<")?;
+                for (i, (k, v)) in d.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    f.write_fmt(format_args!("{:?}: {:?}", k, v))?;
+                }
+                f.write_str(">")
+            }
             Value::NativeFunction(nf) => f.write_fmt(format_args!("{:?}", nf)),
             Value::Object(o) => f.write_fmt(format_args!("{:?}", o)),
         }
     }
 }
 
-impl<'a> From<Literal<'a>> for Value {
-    fn from(value: Literal) -> Self {
-        match value {
-            Literal::Bool(b) => Value::Bool(b),
+/// Values written the way they'd be written in the language, where they can be.
+/// Functions and other values without a literal are shown in angle brackets
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn write_all<'a>(
+            f: &mut std::fmt::Formatter<'_>,
+            items: impl Iterator<Item = (Option<&'a Value>, &'a Value)>,
+            separator: &str,
+        ) -> std::fmt::Result {
+            for (i, (key, value)) in items.enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                if let Some(key) = key {
+                    f.write_fmt(format_args!("{}{}", key, separator))?;
+                }
+                Display::fmt(value, f)?;
+            }
+            Ok(())
+        }
+        match self {
+            Value::Rational(n) => f.write_fmt(format_args!("({} `/ {})", n.numer(), n.denom())),
+            // There are no literals for these, so they're written like other unreadable values
+            Value::Float(n) if n.is_nan() => f.write_str("<nan>"),
+            Value::Float(n) if n.is_infinite() => {
+                f.write_str(if *n > 0.0 { "<inf>" } else { "<-inf>" })
+            }
+            Value::Uninit => f.write_str("<uninit>"),
+            Value::List(l) => {
+                f.write_str("[")?;
+                write_all(f, l.iter().map(|v| (None, v)), "")?;
+                f.write_str("]")
+            }
+            Value::Dictionary(d) => {
+                f.write_str("<")?;
+                write_all(f, d.iter().map(|(k, v)| (Some(k), v)), ": ")?;
+                f.write_str(">")
+            }
+            Value::NativeFunction(_) => f.write_str("<function>"),
+            Value::Object(o) => match o {
+                Object::Closure(_) | Object::Method(_, _) => f.write_str("<function>"),
+                Object::String(s) => {
+                    f.write_str("\"")?;
+                    for c in s.chars() {
+                        match c {
+                            '"' | '\\' | '{' | '}' => f.write_fmt(format_args!("\\{}", c))?,
+                            '\n' => f.write_str("\\n")?,
+                            '\t' => f.write_str("\\t")?,
+                            '\r' => f.write_str("\\r")?,
+                            '\0' => f.write_str("\\0")?,
+                            c => f.write_fmt(format_args!("{}", c))?,
+                        }
+                    }
+                    f.write_str("\"")
+                }
+                Object::Box(b) => f.write_fmt(format_args!("&{}", b.borrow())),
+                Object::Module(m) => {
+                    f.write_str("module {let ")?;
+                    for (i, (name, value)) in m.names.iter().zip(m.values.iter()).enumerate() {
+                        if i > 0 {
+                            f.write_str(", ")?;
+                        }
+                        // Members are bound by bare names, not symbol literals
+                        match name {
+                            Value::Symbol(name) => f.write_str(&name.name())?,
+                            name => Display::fmt(name, f)?,
+                        }
+                        f.write_fmt(format_args!(" {}", value))?;
+                    }
+                    f.write_str("}")
+                }
+                Object::Behaviour(b) => b.fmt(f),
+                Object::Coroutine(c) => f.write_fmt(format_args!("<{:?}>", c)),
+                Object::Launch(_) => f.write_str("<launch>"),
+            },
+            // Numbers, symbols, bools and nil are already written as literals
+            _ => Debug::fmt(self, f),
+        }
+    }
+}
+
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            // 0.0 and -0.0 are equal, so they must hash the same
+            Value::Int(n) => n.hash(state),
+            Value::BigInt(n) => n.hash(state),
+            Value::Rational(n) => n.hash(state),
+            Value::Float(n) if *n == 0.0 => 0u64.hash(state),
+            Value::Float(n) => n.to_bits().hash(state),
+            Value::Bool(b) => b.hash(state),
+            Value::Nil | Value::Uninit => (),
+            Value::Symbol(s) => s.hash(state),
+            Value::List(l) => l.hash(state),
+            Value::Dictionary(d) => {
+                // Combine the entries in an order independent way, so that
+                // equal dictionaries always hash the same
+                let combined = d.iter().fold(0u64, |acc, entry| {
+                    let mut hasher = DefaultHasher::new();
+                    entry.hash(&mut hasher);
+                    acc.wrapping_add(hasher.finish())
+                });
+                d.len().hash(state);
+                combined.hash(state);
+            }
+            Value::NativeFunction(nf) => nf.hash(state),
+            Value::Object(o) => o.hash(state),
+        }
+    }
+}
+
+impl<'a> TryFrom<&Literal<'a>> for Value {
+    type Error = ValueError;
+
+    /// Evaluate a literal at compile time, which fails if it contains any
+    /// non-constant expressions
+    fn try_from(value: &Literal<'a>) -> Result<Self> {
+        fn constant(e: &LocatedExpression) -> Result<Value> {
+            match &e.expression {
+                Expression::Literal(l) => l.try_into(),
+                _ => Err(ValueError::NotConstant),
+            }
+        }
+        Ok(match value {
+            Literal::Bool(b) => Value::Bool(*b),
             Literal::Nil => Value::Nil,
-            Literal::Number(n) => Value::Number(n),
-            Literal::String(s) => Value::Object(Object::String(Rc::new(s))),
-            Literal::Quoted(_) => todo!(),
-            Literal::List(l) => Value::List(
-                l.iter()
-                    .map(|v| match v.expression.clone() {
-                        crate::expression::Expression::Literal(l) => l.into(),
-                        _ => panic!("Can't construct list literal from non const expressions"),
-                    })
-                    .collect(),
+            Literal::Int(n) => Value::Int(*n),
+            Literal::BigInt(n) => number::from_integer(n.clone()),
+            Literal::Float(n) => Value::Float(*n),
+            Literal::String(s) => Value::Object(Object::String(Rc::new(s.clone()))),
+            Literal::Quoted(s) => Value::Symbol(s.into()),
+            Literal::List(l) => Value::List(l.iter().map(constant).collect::<Result<_>>()?),
+            Literal::Dictionary(d) => Value::Dictionary(
+                d.iter()
+                    .map(|(k, v)| Ok((constant(k)?, constant(v)?)))
+                    .collect::<Result<_>>()?,
             ),
-            Literal::Dictionary(_) => todo!(),
-        }
+        })
     }
 }
 
@@ -149,17 +422,50 @@ impl Value {
         }
     }
 
-    pub fn number(&self) -> Result<f64> {
+    /// The value, if it can be used as a dictionary key
+    pub fn key(&self) -> Result<Value> {
+        fn contains_nan(value: &Value) -> bool {
+            match value {
+                Value::Float(f) => f.is_nan(),
+                Value::List(l) => l.iter().any(contains_nan),
+                Value::Dictionary(d) => d.iter().any(|(k, v)| contains_nan(k) || contains_nan(v)),
+                _ => false,
+            }
+        }
+        if contains_nan(self) {
+            Err(ValueError::NaNKey)
+        } else {
+            Ok(self.clone())
+        }
+    }
+
+    pub fn dictionary(&self) -> Result<Dictionary> {
         match self {
-            Value::Number(i) => Ok(*i),
-            _ => Err(ValueError::NotANumber),
+            Value::Dictionary(d) => Ok(d.clone()),
+            _ => Err(ValueError::NotADictionary),
+        }
+    }
+
+    pub fn boxed(&self) -> Result<Rc<RefCell<Value>>> {
+        match self {
+            Value::Object(Object::Box(b)) => Ok(b.clone()),
+            _ => Err(ValueError::NotABox),
         }
     }
 
-    pub fn closure(&self) -> Result<Rc<Closure>> {
+    pub fn coroutine(&self) -> Result<Rc<Coroutine>> {
         match self {
-            Value::Object(Object::Closure(c)) => Ok(c.clone()),
-            _ => Err(ValueError::NotAClosure),
+            Value::Object(Object::Coroutine(c)) => Ok(c.clone()),
+            _ => Err(ValueError::NotACoroutine),
+        }
+    }
+
+    pub fn int(&self) -> Result<i64> {
+        match self {
+            Value::Int(i) => Ok(*i),
+            Value::BigInt(_) => Err(ValueError::Overflow),
+            Value::Rational(_) | Value::Float(_) => Err(ValueError::NotAnInt),
+            _ => Err(ValueError::NotANumber),
         }
     }
 }
@@ -1,47 +1,390 @@
 use std::{
     cell::RefCell,
-    fmt::Debug,
-    iter::repeat,
-    ops::{Deref, RangeBounds},
+    fmt::{Debug, Display, Write},
+    fs,
+    path::{Path, PathBuf},
     rc::Rc,
 };
 
 use crate::{
+    behaviour::Builtins,
+    coroutine::{Coroutine, CoroutineState},
+    diagnostic::{describe_span, render_span},
     frame::Frame,
-    native_function::{self, NativeFunction},
+    module::{compile_source, ModuleError, Modules},
+    native_function::NativeFunction,
     opcode::{FunctionIndex, OpCode, RegisterIndex, ValueIndex},
-    value::{Closure, ClosureType, Function, Object, Placeholder, Value, ValueError},
+    symbol::InternedSymbol,
+    tokeniser::Span,
+    value::{Closure, ClosureType, Dictionary, Function, Object, Placeholder, Value, ValueError},
 };
 
 #[derive(Clone, Debug)]
-pub enum RuntimeError {
+pub enum RuntimeErrorType {
     NoMoreOpCodes,
     NotAFunction,
     NotABoolean,
+    /// Members can only be accessed on modules and dictionaries
+    NotAModule,
     NoLastFrame,
     ValueNotSet,
-    TooManyArguments,
-    NotEnoughArguments,
     Crash,
+    /// The key wasn't in the dictionary being extracted from
+    MissingKey(Value),
+    /// The value didn't match the pattern
+    NoMatch(Value),
+    /// The list doesn't have an element at the index
+    MissingIndex(i64),
+    /// The key isn't in the current dynamic context
+    NotInContext(Value),
     ValueError(ValueError),
+    /// The behaviour's method (.0) isn't implemented for the type (.1)
+    NotImplemented(InternedSymbol, Value),
+    /// An imported module couldn't be loaded
+    ModuleError(ModuleError),
+    /// `yield` was used outside of a coroutine
+    YieldOutsideCoroutine,
+    /// `spawn` was used outside of a launch scope
+    NoLaunchScope,
+    /// The coroutine was resumed while it was already running
+    CoroutineRunning,
+    /// The coroutine was resumed after it had finished
+    CoroutineFinished,
+    /// A coroutine was resumed somewhere other than the VM, which owns the stack
+    ResumedOutsideVM,
+    /// The value was raised and nothing caught it
+    Raised(Value),
+}
+
+impl Display for RuntimeErrorType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeErrorType::NoMoreOpCodes => f.write_str("ran out of opcodes to run"),
+            RuntimeErrorType::NotAFunction => {
+                f.write_str("tried to call something that isn't a function")
+            }
+            RuntimeErrorType::NotABoolean => f.write_str("condition isn't a boolean"),
+            RuntimeErrorType::NotAModule => {
+                f.write_str("tried to get a member of something that isn't a module")
+            }
+            RuntimeErrorType::NoLastFrame => f.write_str("no frame to run"),
+            RuntimeErrorType::ValueNotSet => f.write_str("recursive value used before it was set"),
+            RuntimeErrorType::Crash => f.write_str("crashed"),
+            RuntimeErrorType::MissingKey(key) => {
+                f.write_fmt(format_args!("key {:?} is missing from the dictionary", key))
+            }
+            RuntimeErrorType::NoMatch(value) => {
+                f.write_fmt(format_args!("{:?} doesn't match the pattern", value))
+            }
+            RuntimeErrorType::MissingIndex(index) => {
+                f.write_fmt(format_args!("the list has no element at index {}", index))
+            }
+            RuntimeErrorType::NotInContext(key) => {
+                f.write_fmt(format_args!("key {:?} is not in the current context", key))
+            }
+            RuntimeErrorType::ValueError(e) => Display::fmt(e, f),
+            RuntimeErrorType::NotImplemented(method, type_of) => f.write_fmt(format_args!(
+                "{} isn't implemented for {:?}",
+                method.name(),
+                type_of
+            )),
+            RuntimeErrorType::ModuleError(e) => f.write_fmt(format_args!("{}", e)),
+            RuntimeErrorType::YieldOutsideCoroutine => {
+                f.write_str("yielded outside of a coroutine")
+            }
+            RuntimeErrorType::NoLaunchScope => f.write_str("spawned outside of a launch scope"),
+            RuntimeErrorType::CoroutineRunning => {
+                f.write_str("tried to resume a coroutine which is already running")
+            }
+            RuntimeErrorType::CoroutineFinished => {
+                f.write_str("tried to resume a coroutine which has finished")
+            }
+            RuntimeErrorType::ResumedOutsideVM => {
+                f.write_str("tried to resume a coroutine outside of the VM")
+            }
+            RuntimeErrorType::Raised(value) => f.write_fmt(format_args!("uncaught {:?}", value)),
+        }
+    }
+}
+
+impl RuntimeErrorType {
+    /// Errors which mean the VM itself has gone wrong can't be caught
+    fn is_catchable(&self) -> bool {
+        !matches!(
+            self,
+            RuntimeErrorType::NoMoreOpCodes
+                | RuntimeErrorType::NoLastFrame
+                | RuntimeErrorType::ResumedOutsideVM
+        )
+    }
+
+    /// The name of the kind of error, given to scripts which catch it
+    fn kind(&self) -> &'static str {
+        match self {
+            RuntimeErrorType::NoMoreOpCodes => "no-more-opcodes",
+            RuntimeErrorType::NotAFunction => "not-a-function",
+            RuntimeErrorType::NotABoolean => "not-a-boolean",
+            RuntimeErrorType::NotAModule => "not-a-module",
+            RuntimeErrorType::NoLastFrame => "no-last-frame",
+            RuntimeErrorType::ValueNotSet => "value-not-set",
+            RuntimeErrorType::Crash => "crash",
+            RuntimeErrorType::MissingKey(_) => "missing-key",
+            RuntimeErrorType::NoMatch(_) => "no-match",
+            RuntimeErrorType::MissingIndex(_) => "missing-index",
+            RuntimeErrorType::NotInContext(_) => "not-in-context",
+            RuntimeErrorType::ValueError(e) => match e {
+                ValueError::NotANumber => "not-a-number",
+                ValueError::NotAList => "not-a-list",
+                ValueError::NotADictionary => "not-a-dictionary",
+                ValueError::NotABox => "not-a-box",
+                ValueError::NotConstant => "not-constant",
+                ValueError::NotACollection => "not-a-collection",
+                ValueError::TooManyArguments => "too-many-arguments",
+                ValueError::NotABehaviour => "not-a-behaviour",
+                ValueError::NotACoroutine => "not-a-coroutine",
+                ValueError::UnknownMethod => "unknown-method",
+                ValueError::NotAnInt => "not-an-int",
+                ValueError::Overflow => "overflow",
+                ValueError::DivisionByZero => "division-by-zero",
+                ValueError::NaNKey => "nan-key",
+            },
+            RuntimeErrorType::NotImplemented(_, _) => "not-implemented",
+            RuntimeErrorType::ModuleError(_) => "module-error",
+            RuntimeErrorType::YieldOutsideCoroutine => "yield-outside-coroutine",
+            RuntimeErrorType::NoLaunchScope => "no-launch-scope",
+            RuntimeErrorType::CoroutineRunning => "coroutine-running",
+            RuntimeErrorType::CoroutineFinished => "coroutine-finished",
+            RuntimeErrorType::ResumedOutsideVM => "resumed-outside-vm",
+            RuntimeErrorType::Raised(_) => "raised",
+        }
+    }
+
+    fn with_span(self, span: Span) -> RuntimeError {
+        RuntimeError {
+            error_type: self,
+            span: Some(Box::new(span)),
+            trace: Box::default(),
+        }
+    }
+}
+
+/// An error from running a program, with the span of the expression it happened in
+#[derive(Clone, Debug)]
+pub struct RuntimeError {
+    pub error_type: RuntimeErrorType,
+    /// Boxed, as spans are large and errors are passed around in every `Result`
+    pub span: Option<Box<Span>>,
+    /// The functions which were running when the error wasn't caught, innermost first
+    pub trace: Box<[TraceFrame]>,
+}
+
+/// A function in a stack trace
+#[derive(Clone, Debug)]
+pub struct TraceFrame {
+    /// The symbol the function was bound to, if it was bound by a let
+    pub name: Option<Rc<str>>,
+    /// Whether this is the top level of the file
+    pub top_level: bool,
+    /// Where the function was when the error happened
+    pub span: Option<Span>,
+    /// The number of frames which tail calls replaced to get to this one
+    pub tail_calls: usize,
+}
+
+impl Display for TraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.name, self.top_level) {
+            (Some(name), _) => f.write_fmt(format_args!("at {}", name))?,
+            (None, true) => f.write_str("at <top level>")?,
+            (None, false) => f.write_str("at <anonymous function>")?,
+        }
+        match &self.span {
+            Some(span) => f.write_fmt(format_args!(" ({})", describe_span(span))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.error_type, f)
+    }
+}
+
+impl RuntimeError {
+    /// The error with the line of source it's on, the way rustc shows errors,
+    /// followed by the stack trace if the error didn't happen at the top level
+    pub fn render(&self) -> String {
+        let mut rendered = render_span(&self.to_string(), self.span.as_deref());
+        if let [frame] = &*self.trace {
+            if frame.top_level && frame.tail_calls == 0 {
+                return rendered;
+            }
+        }
+        if !self.trace.is_empty() {
+            rendered.push_str("\nstack trace:");
+        }
+        for frame in &self.trace {
+            write!(rendered, "\n  {}", frame).unwrap();
+            match frame.tail_calls {
+                0 => (),
+                1 => rendered.push_str("\n  … 1 tail call elided"),
+                n => write!(rendered, "\n  … {} tail calls elided", n).unwrap(),
+            }
+        }
+        rendered
+    }
+
+    /// The value a handler is given for the error. Raised values are given as they are,
+    /// other errors as a record of type `error` with the kind, message and location
+    pub fn to_value(&self) -> Value {
+        if let RuntimeErrorType::Raised(value) = &self.error_type {
+            return value.clone();
+        }
+        let symbol = |s: &str| Value::Symbol(InternedSymbol::new(s));
+        let location = match &self.span {
+            Some(span) => Value::Dictionary(Dictionary::from_iter([
+                (
+                    symbol("file"),
+                    Value::Object(Object::String(Rc::new(span.file.to_string()))),
+                ),
+                (symbol("start"), Value::Int(span.start_pos as i64)),
+                (symbol("end"), Value::Int(span.end_pos as i64)),
+            ])),
+            None => Value::Nil,
+        };
+        Value::Dictionary(Dictionary::from_iter([
+            (symbol("type"), symbol("error")),
+            (symbol("kind"), symbol(self.error_type.kind())),
+            (
+                symbol("message"),
+                Value::Object(Object::String(Rc::new(self.to_string()))),
+            ),
+            (symbol("location"), location),
+        ]))
+    }
+}
+
+impl From<RuntimeErrorType> for RuntimeError {
+    fn from(value: RuntimeErrorType) -> Self {
+        RuntimeError {
+            error_type: value,
+            span: None,
+            trace: Box::default(),
+        }
+    }
 }
 
 impl From<ValueError> for RuntimeError {
     fn from(value: ValueError) -> Self {
-        RuntimeError::ValueError(value)
+        RuntimeErrorType::ValueError(value).into()
     }
 }
 
-pub enum CallType {
-    Tail,
-    NonTail(RegisterIndex),
+/// Errors in imported modules are at the span in the module's file
+impl From<ModuleError> for RuntimeError {
+    fn from(value: ModuleError) -> Self {
+        RuntimeError {
+            span: value.span().map(Box::new),
+            error_type: RuntimeErrorType::ModuleError(value),
+            trace: Box::default(),
+        }
+    }
 }
 
 type Result<T> = std::result::Result<T, RuntimeError>;
 
+/// What happens when a function is applied to its arguments
+enum Application {
+    /// A new frame needs to be run to get the result
+    Frame(Rc<Closure>),
+    /// The result is available straight away
+    Value(Value),
+    /// The coroutine needs to be resumed with the value to get the result
+    Resume(Rc<Coroutine>, Value),
+}
+
+/// The number of arguments the function takes before it gives a result
+fn arguments_needed(function: &Value) -> Result<usize> {
+    match function {
+        Value::Object(Object::Closure(closure)) => Ok(closure.arguments_needed()),
+        // The implementation takes any further arguments
+        Value::Object(Object::Method(_, _)) => Ok(1),
+        Value::NativeFunction(nf) => Ok(nf.arguments()),
+        Value::Symbol(_) => Ok(1),
+        Value::Uninit => Err(RuntimeErrorType::ValueNotSet.into()),
+        _ => Err(RuntimeErrorType::NotAFunction.into()),
+    }
+}
+
+/// Apply a function to at most `arguments_needed` arguments
+fn apply(builtins: &Builtins, function: Value, args: Vec<Value>) -> Result<Application> {
+    match function {
+        Value::Object(Object::Closure(closure)) => {
+            let closure = closure.add_arguments(args)?;
+            if closure.arguments_needed() > 0 {
+                return Ok(Application::Value(Value::Object(Object::Closure(Rc::new(
+                    closure,
+                )))));
+            }
+            match &closure.function {
+                ClosureType::Function(_) => Ok(Application::Frame(Rc::new(closure))),
+                ClosureType::NativeFunction(nf) => {
+                    call_native(builtins, nf, closure.arguments.clone())
+                }
+            }
+        }
+        Value::Object(Object::Method(behaviour, method)) => match args.first() {
+            Some(first) => {
+                let implementation = behaviour
+                    .find(method, first)
+                    .ok_or_else(|| RuntimeErrorType::NotImplemented(method, first.type_of()))?;
+                apply(builtins, implementation, args)
+            }
+            None => Ok(Application::Value(Value::Object(Object::Method(
+                behaviour, method,
+            )))),
+        },
+        Value::NativeFunction(nf) => {
+            if args.len() == nf.arguments() {
+                return call_native(builtins, &nf, args);
+            }
+            Ok(Application::Value(nf.call_or_curry(args)?))
+        }
+        Value::Symbol(symbol) => Ok(Application::Value(symbol.call(args)?)),
+        Value::Uninit => Err(RuntimeErrorType::ValueNotSet.into()),
+        _ => Err(RuntimeErrorType::NotAFunction.into()),
+    }
+}
+
+/// Call a native function with all of its arguments
+fn call_native(builtins: &Builtins, nf: &NativeFunction, args: Vec<Value>) -> Result<Application> {
+    if let Some(implementation) = nf.implementation(builtins, &args[0]) {
+        return apply(builtins, implementation, args);
+    }
+    match nf {
+        NativeFunction::Next => Ok(Application::Resume(args[0].coroutine()?, Value::Nil)),
+        NativeFunction::Send => Ok(Application::Resume(args[0].coroutine()?, args[1].clone())),
+        _ => Ok(Application::Value(nf.call(args)?)),
+    }
+}
+
+/// A coroutine whose frames are on the stack
+struct Resumed {
+    coroutine: Rc<Coroutine>,
+    /// The number of frames below the coroutine's
+    base: usize,
+}
+
 #[derive(Default)]
 pub struct VM {
     pub frames: Vec<Frame>,
+    /// Shared with the VMs which run imported modules
+    pub modules: Rc<RefCell<Modules>>,
+    /// The behaviours native functions dispatch through, shared with imported modules
+    pub builtins: Rc<Builtins>,
+    /// The running coroutines, innermost last
+    resumed: Vec<Resumed>,
 }
 
 impl Debug for VM {
@@ -56,79 +399,137 @@ impl Debug for VM {
 
 impl VM {
     pub fn from_bare_function(f: Function) -> Self {
+        VM::with_context(f, Dictionary::default())
+    }
+
+    /// Run the function with the dictionary as its dynamic context
+    pub fn with_context(f: Function, context: Dictionary) -> Self {
         let closure = Rc::new(Closure {
             function: ClosureType::Function(Rc::new(f)),
             captures: vec![],
             arguments: vec![],
         });
         let mut vm = VM::default();
-        vm.frames.push(Frame::new_from_closure(closure, 0));
+        vm.frames.push(Frame::new_from_closure(closure, 0, context));
         vm
     }
 
-    /// Get the call arguments up to an optional limit.
-    /// If there are no more call arguments, set inside call to None
+    /// Step until the program gives a value
+    pub fn run(&mut self) -> Result<Value> {
+        loop {
+            if let Some(v) = self.step()? {
+                return Ok(v);
+            }
+        }
+    }
+
+    /// Step until the outermost frame runs out of opcodes, rather than until it returns,
+    /// then give back the frame. The REPL runs each entry like this so that it can keep
+    /// the registers the entry's bindings are in
+    pub fn run_to_end(&mut self) -> Result<Frame> {
+        loop {
+            if let [frame] = self.frames.as_slice() {
+                if frame.pointer >= frame.function.opcodes.len() {
+                    return self
+                        .frames
+                        .pop()
+                        .ok_or(RuntimeErrorType::NoLastFrame.into());
+                }
+            }
+            self.step()?;
+        }
+    }
+
+    /// Get the value of the module at the path, running it if it hasn't been already
+    pub fn import(&mut self, path: &Path) -> Result<Value> {
+        let path =
+            fs::canonicalize(path).map_err(|e| ModuleError::Io(path.into(), e.to_string()))?;
+        if let Some(value) = self.modules.borrow().cached(&path) {
+            return Ok(value);
+        }
+        self.modules.borrow_mut().start_loading(&path)?;
+        let result = fs::read_to_string(&path)
+            .map_err(|e| ModuleError::Io(path.clone(), e.to_string()).into())
+            .and_then(|source| Ok(compile_source(&source, &path.to_string_lossy())?))
+            .and_then(|function| {
+                let mut vm = VM::from_bare_function(function);
+                vm.modules = self.modules.clone();
+                vm.builtins = self.builtins.clone();
+                vm.run()
+            });
+        self.modules
+            .borrow_mut()
+            .finish_loading(&path, result.as_ref().ok().cloned());
+        result
+    }
+
+    fn run_import(&mut self, path_index: ValueIndex, register: RegisterIndex) -> Result<()> {
+        let path = match self.last_frame()?.get_value_index(path_index).unwrap() {
+            Value::Object(Object::String(path)) => PathBuf::from(path.as_str()),
+            _ => unreachable!(),
+        };
+        let value = self.import(&path)?;
+        let frame = self.last_frame_mut()?;
+        frame.registers[register.0 as usize] = Placeholder::Value(value);
+        frame.pointer += 1;
+        Ok(())
+    }
+
+    /// Get the call arguments up to an optional limit
     pub fn get_call_arguments(&mut self, limit: Option<usize>) -> Result<Vec<Value>> {
         let mut args = vec![];
         while let Some(OpCode::CallArgument(index)) = self.last_frame()?.opcode() {
             if limit.map(|l| args.len() >= l).unwrap_or(false) {
-                return Ok(args);
-            } else {
-                match self.last_frame()?.get_value_index(index) {
-                    Placeholder::Placeholder(_) => unreachable!(),
-                    Placeholder::Value(v) => args.push(v.clone()),
-                }
-                self.increase_pointer(1);
+                break;
             }
+            args.push(self.last_frame()?.get_value_index(index).unwrap());
+            self.increase_pointer(1);
         }
-        self.last_frame_mut()?.inside_call = None;
         Ok(args)
     }
 
+    /// Returns true if the last frame has call arguments left to consume
+    fn has_call_arguments(&self) -> Result<bool> {
+        Ok(matches!(
+            self.last_frame()?.opcode(),
+            Some(OpCode::CallArgument(_))
+        ))
+    }
+
     pub fn run_tail_call(&mut self, function_index: ValueIndex) -> Result<Option<Value>> {
-        self.increase_pointer(1);
-        let args = self.get_call_arguments(None)?;
-        let function = match self.last_frame()?.get_value_index(function_index) {
-            Placeholder::Placeholder(v) => v.borrow().clone(),
-            Placeholder::Value(v) => v,
-        };
+        let function = self.last_frame()?.get_value_index(function_index).unwrap();
+        let args = self.get_call_arguments(Some(arguments_needed(&function)?))?;
+        if self.has_call_arguments()? {
+            // There are more arguments than the function takes, so this frame is still needed.
+            // Call the function normally, then tail call its result with the rest.
+            let scratch = self.last_frame()?.scratch_register();
+            self.last_frame_mut()?.inside_call =
+                Some((ValueIndex::Register(scratch.clone()), None));
+            return self.apply_into_register(function, args, scratch);
+        }
         let position = self.last_frame()?.return_position;
-        self.pop_frame();
-        match function {
-            Value::Object(Object::Closure(closure)) => {
-                let new_closure = Rc::new(closure.add_arguments(args).unwrap());
-                if new_closure.arguments.len() == new_closure.function.arity() {
-                    match new_closure.function.clone() {
-                        ClosureType::Function(_) => {
-                            self.create_and_push_new_frame(new_closure, position);
-                            Ok(None)
-                        }
-                        ClosureType::NativeFunction(nf) => {
-                            let result = nf.call(new_closure.arguments.clone())?;
-                            if self.frames.is_empty() {
-                                Ok(Some(result))
-                            } else {
-                                self.last_frame_mut()?.registers[position] =
-                                    Placeholder::Value(result);
-                                Ok(None)
-                            }
-                        }
-                    }
-                } else {
-                    Err(RuntimeError::NotEnoughArguments)
-                }
-            }
-            Value::NativeFunction(nf) => {
-                let result = nf.call(args)?;
-                if self.frames.is_empty() {
-                    Ok(Some(result))
-                } else {
-                    self.last_frame_mut()?.registers[position] = Placeholder::Value(result);
-                    Ok(None)
-                }
+        // The new frame replaces this one, so it runs in the same dynamic context
+        let context = self.last_frame()?.context();
+        let tail_calls = self.last_frame()?.tail_calls + 1;
+        // Applied before the frame goes, so errors are reported from inside it
+        let application = apply(&self.builtins, function, args)?;
+        // Checked before the frame goes, as that could finish the coroutine being resumed
+        if let Application::Resume(coroutine, _) = &application {
+            if coroutine.is_running() {
+                return Err(RuntimeErrorType::CoroutineRunning.into());
             }
-            x => Err(RuntimeError::NotAFunction),
         }
+        self.pop_frame();
+        let replaced = matches!(application, Application::Frame(_));
+        // A coroutine's last frame can be replaced by another, but otherwise it's done
+        if !replaced {
+            self.finish_coroutine();
+        }
+        let result = self.give(application, position, context)?;
+        if replaced {
+            self.last_frame_mut()?.tail_calls = tail_calls;
+        }
+        Ok(result)
     }
 
     pub fn run_call(
@@ -136,34 +537,234 @@ impl VM {
         function_index: ValueIndex,
         result_index: RegisterIndex,
     ) -> Result<Option<Value>> {
-        self.increase_pointer(1);
-        let function = self.last_frame()?.get_value_index(function_index);
-        match function {
-            Placeholder::Value(Value::Object(Object::Closure(closure))) => {
-                let args = self.get_call_arguments(Some(closure.arguments_needed()))?;
-                let new_closure = closure.add_arguments(args).unwrap();
-                if new_closure.arguments_needed() == 0 {
-                    self.create_and_push_new_frame(Rc::new(new_closure), result_index.0 as usize);
-                } else {
-                    self.last_frame_mut()?.registers[result_index.0 as usize] =
-                        Placeholder::Value(Value::Object(Object::Closure(Rc::new(new_closure))));
+        let function = self.last_frame()?.get_value_index(function_index).unwrap();
+        let args = self.get_call_arguments(Some(arguments_needed(&function)?))?;
+        // If there are arguments left over, the result gets applied to them once it's ready
+        self.last_frame_mut()?.inside_call = if self.has_call_arguments()? {
+            Some((
+                ValueIndex::Register(result_index.clone()),
+                Some(result_index.clone()),
+            ))
+        } else {
+            None
+        };
+        self.apply_into_register(function, args, result_index)
+    }
+
+    /// Apply the function to the arguments, with the result ending up in the given
+    /// register of the last frame
+    fn apply_into_register(
+        &mut self,
+        function: Value,
+        args: Vec<Value>,
+        register: RegisterIndex,
+    ) -> Result<Option<Value>> {
+        let application = apply(&self.builtins, function, args)?;
+        let context = self.last_frame()?.context();
+        self.give(application, register.0 as usize, context)
+    }
+
+    /// Carry out the application, with its result going into the register at the
+    /// position in the last frame
+    fn give(
+        &mut self,
+        application: Application,
+        position: usize,
+        context: Dictionary,
+    ) -> Result<Option<Value>> {
+        match application {
+            Application::Frame(closure) => {
+                self.create_and_push_new_frame(closure, position, context);
+                Ok(None)
+            }
+            Application::Value(value) => self.give_value(position, value),
+            Application::Resume(coroutine, value) => {
+                self.resume(coroutine, value, position, context)
+            }
+        }
+    }
+
+    /// Put the value into the register at the position in the last frame,
+    /// or give it as the program's result if there are no frames left
+    fn give_value(&mut self, position: usize, value: Value) -> Result<Option<Value>> {
+        match self.frames.last_mut() {
+            Some(frame) => {
+                frame.registers[position] = Placeholder::Value(value);
+                Ok(None)
+            }
+            None => Ok(Some(value)),
+        }
+    }
+
+    /// Move the coroutine's frames onto the stack, handing it the value.
+    /// Whatever it next yields or returns goes into the register at the position
+    fn resume(
+        &mut self,
+        coroutine: Rc<Coroutine>,
+        value: Value,
+        position: usize,
+        context: Dictionary,
+    ) -> Result<Option<Value>> {
+        let base = self.frames.len();
+        match coroutine.start_running() {
+            CoroutineState::Start(function) => match apply(&self.builtins, function, vec![])? {
+                Application::Frame(closure) => {
+                    self.create_and_push_new_frame(closure, position, context)
+                }
+                // The body gives its result without running any frames, so can't yield
+                application => {
+                    coroutine.finish();
+                    return self.give(application, position, context);
                 }
+            },
+            CoroutineState::Suspended(mut frames, register) => {
+                frames[0].return_position = position;
+                frames.last_mut().unwrap().registers[usize::from(register)] =
+                    Placeholder::Value(value);
+                self.frames.extend(frames);
             }
-            Placeholder::Value(Value::NativeFunction(nf)) => {
-                let num_args = nf.arguments();
-                let args = self.get_call_arguments(Some(num_args))?;
-                self.last_frame_mut()?.registers[result_index.0 as usize] =
-                    nf.call_or_curry(args)?;
+            CoroutineState::Running => return Err(RuntimeErrorType::CoroutineRunning.into()),
+            CoroutineState::Finished => {
+                coroutine.finish();
+                return Err(RuntimeErrorType::CoroutineFinished.into());
+            }
+        }
+        self.resumed.push(Resumed { coroutine, base });
+        Ok(None)
+    }
+
+    /// Mark the innermost running coroutine as finished if its last frame has gone
+    fn finish_coroutine(&mut self) {
+        if matches!(self.resumed.last(), Some(r) if r.base == self.frames.len()) {
+            self.resumed.pop().unwrap().coroutine.finish();
+        }
+    }
+
+    fn run_yield(
+        &mut self,
+        value_index: ValueIndex,
+        register: RegisterIndex,
+    ) -> Result<Option<Value>> {
+        self.increase_pointer(1);
+        let value = self.last_frame()?.get_value_index(value_index).unwrap();
+        let resumed = self
+            .resumed
+            .pop()
+            .ok_or(RuntimeErrorType::YieldOutsideCoroutine)?;
+        let frames = self.frames.split_off(resumed.base);
+        let position = frames[0].return_position;
+        resumed.coroutine.suspend(frames, register);
+        self.give_value(position, value)
+    }
+
+    fn run_finish_launch(&mut self) -> Result<Option<Value>> {
+        let frame = self.last_frame()?;
+        match frame.launch()?.next_unfinished() {
+            // The pointer stays here, so this runs again once the child yields or finishes
+            Some(child) => {
+                let scratch = frame.scratch_register();
+                let context = frame.context();
+                self.resume(child, Value::Nil, scratch.into(), context)
             }
-            Placeholder::Placeholder(_) => return Err(RuntimeError::ValueNotSet),
-            x => {
-                return Err(RuntimeError::NotAFunction);
+            None => {
+                self.increase_pointer(1);
+                Ok(None)
             }
+        }
+    }
+
+    /// Run the next opcode. Errors are put at the span of the opcode,
+    /// then caught by the innermost handler if there is one.
+    /// Errors which aren't caught are given the stack trace
+    pub fn step(&mut self) -> Result<Option<Value>> {
+        let position = self.position();
+        self.step_uncaught()
+            .map_err(|error| self.locate(error, position))
+            .or_else(|error| self.unwind(error))
+            .map_err(|mut error| {
+                // Errors from imported modules already have the module's frames
+                error.trace = error
+                    .trace
+                    .iter()
+                    .cloned()
+                    .chain(self.trace(position))
+                    .collect();
+                error
+            })
+    }
+
+    /// The frames as a stack trace, innermost first. The innermost is at the opcode at
+    /// the position, and the others at the calls they're waiting on
+    fn trace(&self, position: Option<(usize, usize)>) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, frame)| TraceFrame {
+                name: frame.function.name.clone(),
+                // Unless a tail call replaced it
+                top_level: i == 0 && frame.tail_calls == 0,
+                span: match position {
+                    Some((depth, pointer)) if depth == i + 1 => {
+                        frame.function.locations.get(pointer).cloned().flatten()
+                    }
+                    _ => frame.waiting_span(),
+                },
+                tail_calls: frame.tail_calls,
+            })
+            .collect()
+    }
+
+    /// The number of frames, and the index in the last frame of the opcode about to run
+    fn position(&self) -> Option<(usize, usize)> {
+        let frame = self.frames.last()?;
+        // A call with arguments left over carries on after its last argument, which was
+        // compiled with the call
+        let pointer = match frame.inside_call {
+            Some(_) => frame.pointer - 1,
+            None => frame.pointer,
         };
+        Some((self.frames.len(), pointer))
+    }
+
+    /// Put the error at the span of the opcode at the position, unless it already has one
+    fn locate(&self, error: RuntimeError, position: Option<(usize, usize)>) -> RuntimeError {
+        if error.span.is_some() {
+            return error;
+        }
+        let span = position.and_then(|(depth, pointer)| {
+            self.frames
+                .get(depth - 1)?
+                .function
+                .locations
+                .get(pointer)?
+                .clone()
+        });
+        RuntimeError {
+            span: span.map(Box::new),
+            ..error
+        }
+    }
+
+    /// Pop frames down to the innermost handler, and jump to it with the error.
+    /// Gives back the error if there isn't a handler
+    fn unwind(&mut self, error: RuntimeError) -> Result<Option<Value>> {
+        if !error.error_type.is_catchable() {
+            return Err(error);
+        }
+        let Some(depth) = self.frames.iter().rposition(|f| !f.handlers.is_empty()) else {
+            return Err(error);
+        };
+        self.frames.truncate(depth + 1);
+        // Coroutines whose frames were popped can't be resumed any more
+        while matches!(self.resumed.last(), Some(r) if r.base > depth) {
+            self.resumed.pop().unwrap().coroutine.finish();
+        }
+        self.last_frame_mut()?.catch(error.to_value());
         Ok(None)
     }
 
-    pub fn step(&mut self) -> Result<Option<Value>> {
+    fn step_uncaught(&mut self) -> Result<Option<Value>> {
         match self.last_frame()?.inside_call.clone() {
             Some((function_index, Some(result_index))) => {
                 self.run_call(function_index, result_index)
@@ -173,12 +774,16 @@ impl VM {
                 let oc = self
                     .last_frame()?
                     .opcode()
-                    .ok_or(RuntimeError::NoMoreOpCodes)?;
+                    .ok_or(RuntimeErrorType::NoMoreOpCodes)?;
                 match oc {
                     OpCode::Call(function_index, result_target) => {
+                        self.increase_pointer(1);
                         self.run_call(function_index, result_target)
                     }
-                    OpCode::TailCall(function_index) => self.run_tail_call(function_index),
+                    OpCode::TailCall(function_index) => {
+                        self.increase_pointer(1);
+                        self.run_tail_call(function_index)
+                    }
                     OpCode::DeclareRecursive(index) => {
                         self.last_frame_mut()?.run_declare_recursive(index);
                         Ok(None)
@@ -199,6 +804,45 @@ impl VM {
                             .run_jump_to_position_if_false(check_index, jump as isize)?;
                         Ok(None)
                     }
+                    OpCode::JumpIfNotEqual(a, b, jump) => {
+                        self.last_frame_mut()?
+                            .run_jump_if_not_equal(a, b, jump as isize);
+                        Ok(None)
+                    }
+                    OpCode::JumpIfNotList(list_index, length, has_rest, jump) => {
+                        self.last_frame_mut()?.run_jump_if_not_list(
+                            list_index,
+                            length as usize,
+                            has_rest,
+                            jump as isize,
+                        );
+                        Ok(None)
+                    }
+                    OpCode::JumpIfMissingKey(dict_index, key_index, register_index, jump) => {
+                        self.last_frame_mut()?.run_jump_if_missing_key(
+                            dict_index,
+                            key_index,
+                            register_index,
+                            jump as isize,
+                        );
+                        Ok(None)
+                    }
+                    OpCode::IndexList(list_index, index, register_index) => {
+                        self.last_frame_mut()?.run_index_list(
+                            list_index,
+                            index as usize,
+                            register_index,
+                        )?;
+                        Ok(None)
+                    }
+                    OpCode::ListTail(list_index, from, register_index) => {
+                        self.last_frame_mut()?.run_list_tail(
+                            list_index,
+                            from as usize,
+                            register_index,
+                        )?;
+                        Ok(None)
+                    }
                     OpCode::CopyValue(value_from, value_to) => {
                         self.last_frame_mut()?.run_copy_value(value_from, value_to);
                         Ok(None)
@@ -208,16 +852,134 @@ impl VM {
                         Ok(None)
                     }
                     OpCode::CreateClosure(function_index, register_index) => {
-                        self.run_create_closure(function_index, register_index);
+                        self.run_create_closure(function_index, register_index)?;
                         Ok(None)
                     }
                     OpCode::CaptureValue(_) => unreachable!(),
-                    OpCode::Crash => Err(RuntimeError::Crash),
+                    OpCode::Crash => Err(RuntimeErrorType::Crash.into()),
+                    OpCode::MatchFailed(value_index, span_index) => {
+                        let frame = self.last_frame()?;
+                        // The pattern's span is more precise than the expression's
+                        Err(
+                            RuntimeErrorType::NoMatch(frame.get_value_index(value_index).unwrap())
+                                .with_span(frame.function.spans[usize::from(span_index)].clone()),
+                        )
+                    }
                     OpCode::InsertNativeFunction(native_function, index) => {
                         self.last_frame_mut()?
                             .run_insert_native_function(native_function, index);
                         Ok(None)
                     }
+                    OpCode::InsertBuiltin(builtin, index) => {
+                        let behaviour = self.builtins.get(builtin).clone();
+                        let frame = self.last_frame_mut()?;
+                        frame.registers[index.0 as usize] =
+                            Placeholder::Value(Value::Object(Object::Behaviour(behaviour)));
+                        frame.pointer += 1;
+                        Ok(None)
+                    }
+                    OpCode::BuildList(index) => {
+                        self.last_frame_mut()?.run_build_list(index);
+                        Ok(None)
+                    }
+                    OpCode::ListElement(_) => unreachable!(),
+                    OpCode::BuildDict(index) => {
+                        self.last_frame_mut()?.run_build_dict(index)?;
+                        Ok(None)
+                    }
+                    OpCode::DictEntry(_, _) => unreachable!(),
+                    OpCode::BuildString(index) => {
+                        self.last_frame_mut()?.run_build_string(index);
+                        Ok(None)
+                    }
+                    OpCode::StringPart(_) => unreachable!(),
+                    OpCode::PushContext(context_index) => {
+                        self.last_frame_mut()?.run_push_context(context_index)?;
+                        Ok(None)
+                    }
+                    OpCode::PopContext => {
+                        self.last_frame_mut()?.run_pop_context();
+                        Ok(None)
+                    }
+                    OpCode::LoadContext(key_index, register_index) => {
+                        self.last_frame_mut()?
+                            .run_load_context(key_index, register_index)?;
+                        Ok(None)
+                    }
+                    OpCode::BuildModule(names_index, register_index) => {
+                        self.last_frame_mut()?
+                            .run_build_module(names_index, register_index)?;
+                        Ok(None)
+                    }
+                    OpCode::LoadSlot(module_index, slot, register_index) => {
+                        self.last_frame_mut()?.run_load_slot(
+                            module_index,
+                            slot as usize,
+                            register_index,
+                        )?;
+                        Ok(None)
+                    }
+                    OpCode::LoadMember(module_index, key_index, register_index) => {
+                        self.last_frame_mut()?.run_load_member(
+                            module_index,
+                            key_index,
+                            register_index,
+                        )?;
+                        Ok(None)
+                    }
+                    OpCode::CreateBehaviour(methods_index, register_index) => {
+                        self.last_frame_mut()?
+                            .run_create_behaviour(methods_index, register_index)?;
+                        Ok(None)
+                    }
+                    OpCode::Import(path_index, register_index) => {
+                        self.run_import(path_index, register_index)?;
+                        Ok(None)
+                    }
+                    OpCode::Yield(value_index, register_index) => {
+                        self.run_yield(value_index, register_index)
+                    }
+                    OpCode::Spawn(function_index, register_index) => {
+                        self.last_frame_mut()?
+                            .run_spawn(function_index, register_index)?;
+                        Ok(None)
+                    }
+                    OpCode::PushLaunch => {
+                        self.last_frame_mut()?.run_push_launch();
+                        Ok(None)
+                    }
+                    OpCode::FinishLaunch => self.run_finish_launch(),
+                    OpCode::PushHandler(register_index, offset) => {
+                        self.last_frame_mut()?
+                            .run_push_handler(register_index, offset as isize);
+                        Ok(None)
+                    }
+                    OpCode::PopHandler => {
+                        self.last_frame_mut()?.run_pop_handler();
+                        Ok(None)
+                    }
+                    OpCode::Raise(value_index) => Err(RuntimeErrorType::Raised(
+                        self.last_frame()?.get_value_index(value_index).unwrap(),
+                    )
+                    .into()),
+                    OpCode::MakeBox(value_index, register_index) => {
+                        self.last_frame_mut()?
+                            .run_make_box(value_index, register_index);
+                        Ok(None)
+                    }
+                    OpCode::Dereference(value_index, register_index) => {
+                        self.last_frame_mut()?
+                            .run_dereference(value_index, register_index)?;
+                        Ok(None)
+                    }
+                    OpCode::Extract(dict_index, key_index, register_index) => {
+                        self.last_frame_mut()?.run_extract(
+                            dict_index,
+                            key_index,
+                            register_index,
+                        )?;
+                        Ok(None)
+                    }
                 }
             }
         }
@@ -255,6 +1017,7 @@ impl VM {
         let return_pos = self.last_frame()?.return_position;
         // TODO: will this break garbage collection? No root to value inbetween these calls
         self.pop_frame();
+        self.finish_coroutine();
         if self.frames.is_empty() {
             match value {
                 Placeholder::Placeholder(_) => unreachable!(),
@@ -268,8 +1031,13 @@ impl VM {
 
     /// Creates a new frame from a closure and arguments in the VM's temporary storage
     /// Pushes the new frame onto the current stack of frames
-    fn create_and_push_new_frame(&mut self, closure: Rc<Closure>, result_slot: usize) {
-        let new_frame = Frame::new_from_closure(closure, result_slot);
+    fn create_and_push_new_frame(
+        &mut self,
+        closure: Rc<Closure>,
+        result_slot: usize,
+        context: Dictionary,
+    ) {
+        let new_frame = Frame::new_from_closure(closure, result_slot, context);
         self.frames.push(new_frame);
     }
 
@@ -282,10 +1050,630 @@ impl VM {
     }
 
     fn last_frame_mut(&mut self) -> Result<&mut Frame> {
-        self.frames.last_mut().ok_or(RuntimeError::NoLastFrame)
+        self.frames
+            .last_mut()
+            .ok_or_else(|| RuntimeErrorType::NoLastFrame.into())
     }
 
     fn last_frame(&self) -> Result<&Frame> {
-        self.frames.last().ok_or(RuntimeError::NoLastFrame)
+        self.frames
+            .last()
+            .ok_or_else(|| RuntimeErrorType::NoLastFrame.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        compiler::Compiler,
+        parser::parse_expression,
+        symbol::InternedSymbol,
+        tokeniser::Token,
+        value::{Object, Value, ValueError},
+    };
+
+    use std::rc::Rc;
+
+    use super::{RuntimeError, RuntimeErrorType, VM};
+
+    pub fn run(source: &str) -> Result<Value, RuntimeErrorType> {
+        run_located(source).map_err(|e| e.error_type)
+    }
+
+    fn run_located(source: &str) -> Result<Value, RuntimeError> {
+        let source: Rc<str> = source.into();
+        let tokens = Token::tokenise_source(&source, "")
+            .map(|x| x.unwrap())
+            .collect::<Vec<_>>();
+        let (_, expression) = parse_expression(&tokens).unwrap();
+        let mut compiler = Compiler::new();
+        compiler
+            .compile_expression(None, &expression, true)
+            .unwrap();
+        let mut vm = VM::from_bare_function(compiler.frame_to_function());
+        loop {
+            if let Some(v) = vm.step()? {
+                return Ok(v);
+            }
+        }
+    }
+
+    #[test]
+    fn dictionary_literals_work() {
+        assert_eq!(
+            run("{let x 2; let d <\"a\": x, 1: \"one\">; d `get \"a\"}").unwrap(),
+            Value::Int(2)
+        );
+        assert_eq!(run("get <1: 2> 3").unwrap(), Value::Nil);
+        assert_eq!(run("get <<1: 2>: true> <1: 2>").unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn symbols_look_up_dictionaries() {
+        assert_eq!(run("$a <$a: 2, $b: 3>").unwrap(), Value::Int(2));
+        assert_eq!(run("<$a: 2, $b: 3> `$b").unwrap(), Value::Int(3));
+        assert_eq!(run("{let x ($c <$c: 1>); x}").unwrap(), Value::Int(1));
+        assert_eq!(run("$c <$a: 1>").unwrap(), Value::Nil);
+        assert_eq!(run("$a `= $a").unwrap(), Value::Bool(true));
+        assert!(run("$a 1").is_err());
+    }
+
+    #[test]
+    fn extract_binds_symbols() {
+        assert_eq!(
+            run("{extract a b : <$a: 1, $b: 2>; a `+ b}").unwrap(),
+            Value::Int(3)
+        );
+        assert_eq!(
+            run("(|d| {extract x : d; x `* 2}) <$x: 4>").unwrap(),
+            Value::Int(8)
+        );
+        assert!(matches!(
+            run("{extract a c : <$a: 1>; a}"),
+            Err(RuntimeErrorType::MissingKey(Value::Symbol(s))) if &*s.name() == "c"
+        ));
+    }
+
+    #[test]
+    fn boxes_are_shared_between_closures() {
+        assert_eq!(run("@&2").unwrap(), Value::Int(2));
+        assert_eq!(
+            run(
+                "{let counter &0, inc || put counter (@counter `+ 1), read || @counter;
+                  inc!; inc!; read!}"
+            )
+            .unwrap(),
+            Value::Int(2)
+        );
+        assert_eq!(run("{let a &1, b &1; a `= b}").unwrap(), Value::Bool(false));
+        assert!(run("@1").is_err());
+    }
+
+    #[test]
+    fn contexts_are_dynamically_scoped() {
+        assert_eq!(run("with <$a: 2> ^$a").unwrap(), Value::Int(2));
+        assert_eq!(
+            run("{let f || ^$a; with <$a: 1> f!}").unwrap(),
+            Value::Int(1)
+        );
+        assert_eq!(
+            run("with <$a: 1, $b: 2> with <$a: 3> (^$a `+ ^$b)").unwrap(),
+            Value::Int(5)
+        );
+        assert_eq!(
+            run("{let g || ^$a, f || with <$a: 5> g!; with <$a: 1> (f! `+ ^$a)}").unwrap(),
+            Value::Int(6)
+        );
+        assert!(matches!(
+            run("{let f || ^$a; with <$a: 1> f!; f!}"),
+            Err(RuntimeErrorType::NotInContext(Value::Symbol(s))) if &*s.name() == "a"
+        ));
+    }
+
+    #[test]
+    fn match_checks_patterns_in_order() {
+        assert_eq!(
+            run("match 3 { 1 ~ \"one\"; 3 ~ \"three\"; else \"other\" }").unwrap(),
+            Value::Object(Object::String(Rc::new("three".into())))
+        );
+        assert_eq!(
+            run("match $b { $a ~ 1; $b ~ 2; else 3 }").unwrap(),
+            Value::Int(2)
+        );
+        assert_eq!(run("match 5 { _ ~ 1; else 2 }").unwrap(), Value::Int(1));
+        assert_eq!(
+            run("match [1] { [a, b] ~ 1; else 2 }").unwrap(),
+            Value::Int(2)
+        );
+        assert_eq!(
+            run("match [1, [2, 3]] { [x, [y, z]] ~ x `+ y `+ z; else 0 }").unwrap(),
+            Value::Int(6)
+        );
+        assert_eq!(
+            run("match <$a: 1> { <x: $b> ~ x; <x: $a> ~ x `+ 10; else 0 }").unwrap(),
+            Value::Int(11)
+        );
+        assert_eq!(
+            run("match [1, 2, 3] { [a, ..rest] ~ rest; else nil }").unwrap(),
+            Value::List(im::vector![Value::Int(2), Value::Int(3)])
+        );
+        assert_eq!(
+            run("{let x 1; match [2] { [x] ~ x; else nil }; x}").unwrap(),
+            Value::Int(1)
+        );
+        assert_eq!(
+            run("{letrec sum |xs| match xs { [] ~ 0; [h, ..t] ~ h `+ (sum t); else nil }; sum [1, 2, 3]}")
+                .unwrap(),
+            Value::Int(6)
+        );
+    }
+
+    #[test]
+    fn destructuring_binds_parts_of_values() {
+        assert_eq!(
+            run("{let [h, ..t] [1, 2, 3], <x: $x> <$x: 4>; [h, t, x]}").unwrap(),
+            Value::List(im::vector![
+                Value::Int(1),
+                Value::List(im::vector![Value::Int(2), Value::Int(3)]),
+                Value::Int(4)
+            ])
+        );
+        assert_eq!(
+            run("(|[a, b] <x: $x>| a `+ b `+ x) [1, 2] <$x: 3>").unwrap(),
+            Value::Int(6)
+        );
+        assert_eq!(run("(|_ y| y) 1 2").unwrap(), Value::Int(2));
+        assert!(matches!(
+            run_located("{let [a, b] [1]; a}"),
+            Err(RuntimeError {
+                error_type: RuntimeErrorType::NoMatch(_),
+                span: Some(span),
+                ..
+            }) if span.start_pos == 5 && span.end_pos == 11
+        ));
+        assert!(matches!(
+            run("let [a, b] [1]"),
+            Err(RuntimeErrorType::NoMatch(_))
+        ));
+        assert!(matches!(
+            run_located("(|<x: $x>| x) <$y: 1>"),
+            Err(RuntimeError {
+                error_type: RuntimeErrorType::NoMatch(_),
+                span: Some(span),
+                ..
+            }) if span.start_pos == 2 && span.end_pos == 9
+        ));
+    }
+
+    #[test]
+    fn module_members_are_accessible() {
+        assert_eq!(
+            run("{let m module { let a 1, b a `+ 1 }; m.b}").unwrap(),
+            Value::Int(2)
+        );
+        assert_eq!(
+            run("{let m module { letrec even |n| cond { n `= 0 ~ true; else odd (n `- 1) }, odd |n| cond { n `= 0 ~ false; else even (n `- 1) } }; m.even 10}")
+                .unwrap(),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            run("{let m module { let a 3 }; let f || m.a; f!}").unwrap(),
+            Value::Int(3)
+        );
+        assert_eq!(run("(|m| m.a) <$a: 4>").unwrap(), Value::Int(4));
+        assert_eq!(run("(|m| m.a) module { let a 5 }").unwrap(), Value::Int(5));
+    }
+
+    #[test]
+    fn behaviours_dispatch_on_the_first_argument() {
+        let shapes = "let Shape behaviour { area, name };
+            implement Shape $square <$area: |s| ($side s) `* ($side s)>;
+            implement Shape $number module { let area |n| n `* n };";
+        assert_eq!(
+            run(&format!(
+                "{{{} Shape.area <$type: $square, $side: 3>}}",
+                shapes
+            ))
+            .unwrap(),
+            Value::Int(9)
+        );
+        assert_eq!(
+            run(&format!("{{{} Shape.area 4}}", shapes)).unwrap(),
+            Value::Int(16)
+        );
+        assert!(matches!(
+            run(&format!("{{{} Shape.name 4}}", shapes)),
+            Err(RuntimeErrorType::NotImplemented(_, _))
+        ));
+        assert!(matches!(
+            run("implement (behaviour { a }) $number <$b: 1>"),
+            Err(RuntimeErrorType::ValueError(ValueError::UnknownMethod))
+        ));
+        assert_eq!(
+            run("type (module { let type $point })").unwrap(),
+            Value::Symbol(InternedSymbol::new("point"))
+        );
+    }
+
+    #[test]
+    fn builtins_are_extensible() {
+        let point = "implement Eq $point <$=: |a b| ($x a) `= ($x b)>;
+            implement Ord $point <$lt: |a b| ($x a) `lt ($x b)>;";
+        assert_eq!(
+            run(&format!(
+                "{{{} <$type: $point, $x: 1, $y: 2> `= <$type: $point, $x: 1, $y: 3>}}",
+                point
+            ))
+            .unwrap(),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            run(&format!(
+                "{{{} <$type: $point, $x: 1> `lt <$type: $point, $x: 2>}}",
+                point
+            ))
+            .unwrap(),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            run("<$x: 1, $y: 2> `= <$x: 1, $y: 3>").unwrap(),
+            Value::Bool(false)
+        );
+        // Implementations from earlier runs are gone
+        assert_eq!(
+            run("<$type: $point, $x: 1, $y: 2> `= <$type: $point, $x: 1, $y: 3>").unwrap(),
+            Value::Bool(false)
+        );
+    }
+
+    #[test]
+    fn generators_resume_where_they_yielded() {
+        let counter = "letrec count |n| {yield n; count (n `+ 1)};
+            let g generator || count 0;";
+        assert_eq!(
+            run(&format!("{{{} next g; next g; next g}}", counter)).unwrap(),
+            Value::Int(2)
+        );
+        assert_eq!(
+            run("{let g generator || { let x yield 1; x `+ 1 }; next g; send g 5}").unwrap(),
+            Value::Int(6)
+        );
+        assert_eq!(
+            run("{let g generator || { yield 1; 2 }; next g; [done g, next g, done g]}").unwrap(),
+            Value::List(im::vector![
+                Value::Bool(false),
+                Value::Int(2),
+                Value::Bool(true)
+            ])
+        );
+        assert!(matches!(
+            run("{let g generator || 1; next g; next g}"),
+            Err(RuntimeErrorType::CoroutineFinished)
+        ));
+        // Even when it's the last thing the generator does
+        let error = run_located("{letrec g generator || next g; next g}").unwrap_err();
+        assert!(matches!(
+            error.error_type,
+            RuntimeErrorType::CoroutineRunning
+        ));
+        let span = error.span.unwrap();
+        assert_eq!((span.start_pos, span.end_pos), (23, 29));
+        assert!(matches!(
+            run("yield 1"),
+            Err(RuntimeErrorType::YieldOutsideCoroutine)
+        ));
+    }
+
+    #[test]
+    fn generators_can_resume_each_other() {
+        assert_eq!(
+            run("{let inner generator || { yield 1; yield 2 };
+                 let outer generator || { yield (next inner) `* 10; yield (next inner) `* 10 };
+                 (next outer) `+ (next outer)}")
+            .unwrap(),
+            Value::Int(30)
+        );
+    }
+
+    #[test]
+    fn launch_runs_children_to_completion() {
+        assert_eq!(
+            run("{let log &[];
+                 let write |x| put log (push @log x);
+                 launch {
+                   spawn || { write 1; yield nil; write 3 };
+                   spawn || { write 2; yield nil; write 4 };
+                   write 0
+                 };
+                 @log}")
+            .unwrap(),
+            Value::List(im::vector![
+                Value::Int(0),
+                Value::Int(1),
+                Value::Int(2),
+                Value::Int(3),
+                Value::Int(4)
+            ])
+        );
+        assert_eq!(
+            *run("launch { let b &1; spawn || put b 2; b }")
+                .unwrap()
+                .boxed()
+                .unwrap()
+                .borrow(),
+            Value::Int(2)
+        );
+        assert!(matches!(
+            run("spawn || 1"),
+            Err(RuntimeErrorType::NoLaunchScope)
+        ));
+    }
+
+    #[test]
+    fn raised_values_are_caught_by_the_innermost_try() {
+        assert_eq!(run("try raise 3 catch |e| e `+ 1").unwrap(), Value::Int(4));
+        assert_eq!(
+            run("{let f |x| raise x; try (f 2) catch |e| e `* 10}").unwrap(),
+            Value::Int(20)
+        );
+        assert_eq!(
+            run("try (try raise 1 catch |e| raise (e `+ 1)) catch |e| e").unwrap(),
+            Value::Int(2)
+        );
+        assert_eq!(
+            run("with <$a: 1> (try (with <$a: 2> raise nil) catch |e| ^$a)").unwrap(),
+            Value::Int(1)
+        );
+        assert_eq!(
+            run("{let g generator || raise 1; [try (next g) catch |e| e, done g]}").unwrap(),
+            Value::List(im::vector![Value::Int(1), Value::Bool(true)])
+        );
+        assert_eq!(
+            run("{let f |x| try (x `+ 1) catch |e| 0; [f 1, f nil]}").unwrap(),
+            Value::List(im::vector![Value::Int(2), Value::Int(0)])
+        );
+        assert!(matches!(
+            run("raise 3"),
+            Err(RuntimeErrorType::Raised(Value::Int(3)))
+        ));
+    }
+
+    #[test]
+    fn runtime_errors_are_caught_as_error_values() {
+        let kind = |source: &str| run(&format!("try {} catch |e| [type e, $kind e]", source));
+        let error = |kind: &str| {
+            Value::List(im::vector![
+                Value::Symbol(InternedSymbol::new("error")),
+                Value::Symbol(InternedSymbol::new(kind))
+            ])
+        };
+        assert_eq!(kind("(1 `+ $a)").unwrap(), error("not-a-number"));
+        assert_eq!(kind("(ind [1] 5)").unwrap(), error("missing-index"));
+        assert_eq!(
+            run(r#"try ("a" `+ 1) catch |e| $message e"#).unwrap(),
+            Value::Object(Object::String(Rc::new("expected a number".into())))
+        );
+        assert_eq!(
+            run("try {let [a] 1; a} catch |e| $start ($location e)").unwrap(),
+            Value::Int(9)
+        );
+    }
+
+    #[test]
+    fn errors_are_at_the_expression_which_failed() {
+        let span = |source| run_located(source).unwrap_err().span.unwrap();
+        let inside_function = span("{let f |x| x `+ 1; f nil}");
+        assert_eq!(
+            (inside_function.start_pos, inside_function.end_pos),
+            (11, 17)
+        );
+        // Calls with arguments left over are at the whole call
+        let extra_arguments = span("{let f |x| x; f 1 2}");
+        assert_eq!(
+            (extra_arguments.start_pos, extra_arguments.end_pos),
+            (14, 19)
+        );
+        assert_eq!(
+            run("try (1 `+ nil) catch |e| $start ($location e)").unwrap(),
+            Value::Int(5)
+        );
+        assert!(run_located("1 `/ 0")
+            .unwrap_err()
+            .render()
+            .starts_with("error: division by zero\n"));
+    }
+
+    #[test]
+    fn uncaught_errors_have_a_stack_trace() {
+        let error = run_located(
+            "{letrec count |n| cond {n `= 0 ~ 1 `+ nil; else count (n `- 1)};
+              let outer |x| {let y (count x); y};
+              [outer 998]}",
+        )
+        .unwrap_err();
+        let trace = error
+            .trace
+            .iter()
+            .map(|f| (f.name.as_deref(), f.top_level, f.tail_calls))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            trace,
+            vec![
+                (Some("count"), false, 998),
+                (Some("outer"), false, 0),
+                (None, true, 0)
+            ]
+        );
+        // Each frame is at the call it's waiting on
+        let span = error.trace[1].span.as_ref().unwrap();
+        assert_eq!((span.start_pos, span.end_pos), (101, 108));
+        let rendered = error.render();
+        assert!(rendered.contains("\nstack trace:\n  at count ("));
+        assert!(rendered.contains("\n  … 998 tail calls elided\n  at outer ("));
+        assert!(rendered.ends_with("\n  at <top level> (:3:16)"));
+        // Errors at the top level don't need one
+        assert!(!run_located("1 `+ nil")
+            .unwrap_err()
+            .render()
+            .contains("stack trace"));
+        let anonymous = run_located("[(|x| x `+ nil) 1]").unwrap_err();
+        assert_eq!(
+            anonymous.trace[0].to_string(),
+            "at <anonymous function> (:1:7)"
+        );
+    }
+
+    #[test]
+    fn unicode_symbols_and_strings_work() {
+        assert_eq!(
+            run("{let π 3, naïve |ç| {ç `+ π}; [naïve 1, len \"日本語\"]}").unwrap(),
+            Value::List(im::vector![Value::Int(4), Value::Int(3)])
+        );
+    }
+
+    #[test]
+    fn strings_are_interpolated() {
+        assert_eq!(
+            run(r#"{let n 2; "total: {n `+ 1}, {[n, 1.5]} {"in{n}ner"}\{"}"#).unwrap(),
+            Value::Object(Object::String(Rc::new("total: 3, [2, 1.5] in2ner{".into())))
+        );
+        // Values which aren't strings are shown the way print shows them
+        assert_eq!(
+            run(r#""{["a", &1, 1 `/ 3]}""#).unwrap(),
+            Value::Object(Object::String(Rc::new(r#"["a", &1, (1 `/ 3)]"#.into())))
+        );
+    }
+
+    #[test]
+    fn values_display_as_literals() {
+        assert_eq!(
+            run(r#"[1, 1 `/ 3, 2.5, $sym, nil, "a\n\{x}", <"k": [true]>, &1]"#)
+                .unwrap()
+                .to_string(),
+            r#"[1, (1 `/ 3), 2.5, $sym, nil, "a\n\{x\}", <"k": [true]>, &1]"#
+        );
+        assert_eq!(
+            run("module {let a 1, f |x| x}").unwrap().to_string(),
+            "module {let a 1, f <function>}"
+        );
+        assert_eq!(
+            run("[1.0 `/ 0.0, -1.0 `/ 0.0, 0.0 `/ 0.0]")
+                .unwrap()
+                .to_string(),
+            "[<inf>, <-inf>, <nan>]"
+        );
+    }
+
+    #[test]
+    fn ints_and_floats_are_separate() {
+        assert_eq!(run("1 `+ 2").unwrap(), Value::Int(3));
+        assert_eq!(run("1 `+ 2.0").unwrap(), Value::Float(3.0));
+        assert_eq!(run("7 `/ 2.0").unwrap(), Value::Float(3.5));
+        assert_eq!(
+            run("[7 `div 2, 7 `mod 2, 6 `band 3]").unwrap(),
+            Value::List(im::vector![Value::Int(3), Value::Int(1), Value::Int(2)])
+        );
+        assert_eq!(run("1 `= 1.0").unwrap(), Value::Bool(false));
+        assert_eq!(
+            format!("{:?}", run("[1 `/ 2, 4 `/ 2, (1 `/ 3) `* 3.0]").unwrap()),
+            "[1/2, 2, 1.0]"
+        );
+        assert!(matches!(
+            run("ind [1, 2] 1.7"),
+            Err(RuntimeErrorType::ValueError(ValueError::NotAnInt))
+        ));
+        assert!(matches!(
+            run("ind [1, 2] 9223372036854775808"),
+            Err(RuntimeErrorType::ValueError(ValueError::Overflow))
+        ));
+    }
+
+    #[test]
+    fn non_constant_lists_are_built() {
+        assert_eq!(
+            run("{let x 1; [x, x `+ 1, [x], <$a: x>]}").unwrap(),
+            Value::List(im::vector![
+                Value::Int(1),
+                Value::Int(2),
+                Value::List(im::vector![Value::Int(1)]),
+                run("<$a: 1>").unwrap()
+            ])
+        );
+        assert_eq!(run("((|x| [x, 2]) 1) `ind 0").unwrap(), Value::Int(1));
+    }
+
+    #[test]
+    fn over_application_applies_the_result() {
+        assert_eq!(run("(|a| |b| a `+ b) 1 2").unwrap(), Value::Int(3));
+        assert_eq!(run("((|a| |b| a `+ b) 1 2) `+ 1").unwrap(), Value::Int(4));
+        assert_eq!(
+            run("((|a| |b| |c| [a, b, c]) 1 2 3) `ind 2").unwrap(),
+            Value::Int(3)
+        );
+        assert_eq!(
+            run("{let f || |x| x `* 2; (f 4) `+ 1}").unwrap(),
+            Value::Int(9)
+        );
+    }
+
+    #[test]
+    fn native_functions_curry_and_over_apply() {
+        assert_eq!(run("(|f| f) + 1 2").unwrap(), Value::Int(3));
+        assert_eq!(run("{let x ((|f| f) + 1 2); x}").unwrap(), Value::Int(3));
+        assert_eq!(run("{let p + 1; p 2}").unwrap(), Value::Int(3));
+        assert_eq!(
+            run("{let f |x| + x; let g (f 1); g 2}").unwrap(),
+            Value::Int(3)
+        );
+        assert!(matches!(run("1 2"), Err(RuntimeErrorType::NotAFunction)));
+    }
+
+    #[test]
+    fn example_programs_run() {
+        assert_eq!(
+            run(include_str!("programs/fib.maxlang")).unwrap(),
+            Value::Int(10946)
+        );
+        assert_eq!(
+            run(include_str!("programs/curry.maxlang")).unwrap(),
+            Value::Int(6)
+        );
+        assert_eq!(
+            run(include_str!("programs/closure_capture.maxlang")).unwrap(),
+            Value::Int(3)
+        );
+        assert_eq!(
+            run(include_str!("programs/lists.maxlang")).unwrap(),
+            Value::Int(2)
+        );
+        let factorial = format!(
+            "{:?}",
+            run(include_str!("programs/fac_tail_recursive.maxlang")).unwrap()
+        );
+        assert_eq!(factorial.len(), 2568);
+        assert!(factorial.starts_with("402387260077093773543702433923003985719374864210"));
+        assert!(factorial.ends_with(&"0".repeat(249)));
+    }
+
+    #[test]
+    fn dictionary_functions_work() {
+        assert_eq!(
+            run("len (insert (remove <\"a\": 1, \"b\": 2> \"a\") \"c\" 3)").unwrap(),
+            Value::Int(2)
+        );
+        assert_eq!(
+            run("values (insert <> 1 2)").unwrap(),
+            Value::List(im::vector![Value::Int(2)])
+        );
+        assert!(run("keys [1, 2]").is_err());
+        // NaN isn't equal to itself, so it would make a key which can never be found
+        assert!(matches!(
+            run("{let n 0.0 `/ 0.0; insert <> [n] 1}"),
+            Err(RuntimeErrorType::ValueError(ValueError::NaNKey))
+        ));
+        assert!(matches!(
+            run("{let n 0.0 `/ 0.0; <n: 1>}"),
+            Err(RuntimeErrorType::ValueError(ValueError::NaNKey))
+        ));
     }
 }